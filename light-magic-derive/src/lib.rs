@@ -0,0 +1,160 @@
+//! Derive macros for [`light-magic`](https://docs.rs/light-magic), re-exported with its `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, ToTokens};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Index, LitStr, Member, Type,
+};
+
+/// Derives `PrimaryKey` from the fields marked with `#[primary_key]`.
+///
+/// - `#[primary_key]`: Part of the primary key, several fields form a composite key (a tuple in field order).
+/// - `#[primary_key(auto_increment)]`: Enables generated keys with `Table::insert_with`.
+/// - `#[index]` / `#[unique]`: Declares a (unique) secondary index named after the field,
+///   `#[index(name = "...")]` overrides the name.
+///
+/// Key and indexed fields have to implement `Clone`.
+#[proc_macro_derive(PrimaryKey, attributes(primary_key, index, unique))]
+pub fn derive_primary_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    primary_key(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Derives `DataStore` for a database struct.
+#[proc_macro_derive(DataStore)]
+pub fn derive_data_store(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    marker_impl(input, quote!(::light_magic::atomic::DataStore)).into()
+}
+
+/// Derives `EncryptedDataStore` for a database struct.
+#[proc_macro_derive(EncryptedDataStore)]
+pub fn derive_encrypted_data_store(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    marker_impl(input, quote!(::light_magic::encrypted::EncryptedDataStore)).into()
+}
+
+/// Implements a trait, that has only provided items.
+fn marker_impl(input: DeriveInput, path: TokenStream2) -> TokenStream2 {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote! {
+        impl #impl_generics #path for #name #ty_generics #where_clause {}
+    }
+}
+
+fn primary_key(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                input.span(),
+                "PrimaryKey can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut keys: Vec<(Member, &Type)> = Vec::new();
+    let mut auto_increment = false;
+    let mut indexes = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        for attr in &field.attrs {
+            if attr.path().is_ident("primary_key") {
+                if !matches!(attr.meta, syn::Meta::Path(_)) {
+                    attr.parse_nested_meta(|meta| {
+                        if meta.path.is_ident("auto_increment") {
+                            auto_increment = true;
+                            Ok(())
+                        } else {
+                            Err(meta.error("expected `auto_increment`"))
+                        }
+                    })?;
+                }
+                keys.push((member.clone(), &field.ty));
+            } else if attr.path().is_ident("index") || attr.path().is_ident("unique") {
+                let mut name = match &member {
+                    Member::Named(ident) => LitStr::new(&ident.to_string(), ident.span()),
+                    Member::Unnamed(index) => LitStr::new(&index.index.to_string(), index.span),
+                };
+                if !matches!(attr.meta, syn::Meta::Path(_)) {
+                    attr.parse_nested_meta(|meta| {
+                        if meta.path.is_ident("name") {
+                            name = meta.value()?.parse()?;
+                            Ok(())
+                        } else {
+                            Err(meta.error("expected `name = \"...\"`"))
+                        }
+                    })?;
+                }
+                let method = if attr.path().is_ident("unique") {
+                    quote!(unique)
+                } else {
+                    quote!(add)
+                };
+                let ty = &field.ty;
+                indexes.push(quote! {
+                    indexes.#method(#name, |row: &Self| -> #ty {
+                        ::std::clone::Clone::clone(&row.#member)
+                    });
+                });
+            }
+        }
+    }
+
+    let (key_type, key) = match keys.as_slice() {
+        [] => {
+            return Err(Error::new(
+                input.ident.span(),
+                "expected a field marked with #[primary_key]",
+            ))
+        }
+        [(member, ty)] => (
+            ty.to_token_stream(),
+            quote!(::std::clone::Clone::clone(&self.#member)),
+        ),
+        keys => {
+            let types = keys.iter().map(|(_, ty)| ty);
+            let members = keys.iter().map(|(member, _)| member);
+            (
+                quote!((#(#types),*)),
+                quote!((#(::std::clone::Clone::clone(&self.#members)),*)),
+            )
+        }
+    };
+    if auto_increment && keys.len() > 1 {
+        return Err(Error::new(
+            input.ident.span(),
+            "auto_increment is not supported for composite keys",
+        ));
+    }
+
+    let indexes_fn = (!indexes.is_empty()).then(|| {
+        quote! {
+            fn indexes(indexes: &mut ::light_magic::index::Indexes<Self>) {
+                #(#indexes)*
+            }
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::light_magic::table::PrimaryKey for #name #ty_generics #where_clause {
+            type PrimaryKeyType = #key_type;
+            const AUTO_INCREMENT: bool = #auto_increment;
+
+            fn primary_key(&self) -> Self::PrimaryKeyType {
+                #key
+            }
+
+            #indexes_fn
+        }
+    })
+}
@@ -0,0 +1,370 @@
+//! Async database for the tokio runtime.
+//!
+//! [`AsyncDatabase`] uses async locks, so waiting for a lock never blocks a worker thread.
+//! Writes are persisted by a background task, which takes a read lock and does the file I/O on
+//! a blocking thread, so readers continue while the database is saved. Several writes in a row
+//! are coalesced into a single save. Use [`AsyncDatabaseWrite::commit`] to wait until the
+//! changes are saved.
+//!
+//! ```
+//! use light_magic::{
+//!     asynchronous::AsyncDatabase,
+//!     atomic::DataStore,
+//!     serde::{Deserialize, Serialize},
+//! };
+//!
+//! #[derive(Default, Serialize, Deserialize)]
+//! struct Database {
+//!     counter: usize,
+//! }
+//!
+//! impl DataStore for Database {}
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let db = AsyncDatabase::<Database>::open_in_memory();
+//! db.write().await.counter += 1;
+//! assert_eq!(db.read().await.counter, 1);
+//! # }
+//! ```
+
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    sync::{mpsc, watch, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    task,
+};
+use tracing::{error, info};
+
+use crate::{
+    atomic::{AtomicDatabase, DataStore, OpenOptions, Storage},
+    error::Error,
+};
+
+/// Asynchronously synchronized wrapper, that saves changes in the background when it has a path.
+///
+/// Cloning it is cheap, all clones share the same data. The background task makes a final save
+/// when the last clone is dropped, use [`AsyncDatabase::close`] to wait for it.
+pub struct AsyncDatabase<T: DataStore> {
+    shared: Arc<Shared<T>>,
+    /// Wakes up the background task, which exits once all senders are dropped.
+    changes: Option<mpsc::Sender<()>>,
+}
+
+struct Shared<T> {
+    storage: Option<Storage>,
+    data: RwLock<T>,
+    /// Serializes the saves, which only hold a read lock on the data.
+    saving: Mutex<()>,
+    /// Number of writes so far.
+    generation: AtomicU64,
+    /// Latest saved generation and whether saving it failed.
+    saved: watch::Sender<(u64, bool)>,
+    /// Number of clones of the database.
+    handles: AtomicUsize,
+    task: Mutex<Option<task::JoinHandle<()>>>,
+}
+
+impl<T: DataStore + DeserializeOwned + Send + Sync + 'static> AsyncDatabase<T> {
+    /// Creates a database in memory.
+    pub fn open_in_memory() -> Self {
+        Self::from_parts(None, T::default())
+    }
+
+    /// Opens a Database by the specified path. If the Database doesn't exist, this will create a new one!
+    pub async fn open<P: AsRef<Path>>(db: P) -> Result<Self, Error> {
+        Self::open_with(db, OpenOptions::default()).await
+    }
+
+    /// Opens a Database by the specified path with the given [`OpenOptions`].
+    /// If the Database doesn't exist, this will create a new one!
+    ///
+    /// Has to be called within a tokio runtime, which runs the background task.
+    pub async fn open_with<P: AsRef<Path>>(db: P, options: OpenOptions) -> Result<Self, Error> {
+        let path: PathBuf = db.as_ref().into();
+        let db = task::spawn_blocking(move || {
+            if path.exists() {
+                AtomicDatabase::<T>::load_with(&path, options)
+            } else {
+                AtomicDatabase::<T>::create_with(&path, options)
+            }
+        })
+        .await
+        .map_err(join_error)??;
+        let (storage, data) = db.into_parts();
+        Ok(Self::from_parts(storage, data))
+    }
+
+    fn from_parts(storage: Option<Storage>, data: T) -> Self {
+        // Read-only databases are never saved
+        let persistent = storage.as_ref().map_or(false, |s| !s.is_read_only());
+        let shared = Arc::new(Shared {
+            storage,
+            data: RwLock::new(data),
+            saving: Mutex::new(()),
+            generation: AtomicU64::new(0),
+            saved: watch::channel((0, false)).0,
+            handles: AtomicUsize::new(1),
+            task: Mutex::new(None),
+        });
+        let changes = persistent.then(|| {
+            let (sender, receiver) = mpsc::channel(1);
+            let task = tokio::spawn(persist_task(shared.clone(), receiver));
+            *shared.task.lock() = Some(task);
+            sender
+        });
+        Self { shared, changes }
+    }
+
+    /// Locks the database for reading.
+    pub async fn read(&self) -> AsyncDatabaseRead<'_, T> {
+        AsyncDatabaseRead {
+            data: self.shared.data.read().await,
+        }
+    }
+
+    /// Locks the database for writing. The changes are saved in the background after the guard is dropped.
+    pub async fn write(&self) -> AsyncDatabaseWrite<'_, T> {
+        AsyncDatabaseWrite {
+            db: self,
+            data: Some(self.shared.data.write().await),
+        }
+    }
+
+    /// Locks the database for writing, failing with [`Error::Dirty`] if a previous save failed
+    /// and with [`Error::ReadOnly`] if it was opened read-only.
+    pub async fn try_write(&self) -> Result<AsyncDatabaseWrite<'_, T>, Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let guard = self.write().await;
+        if self.is_dirty() {
+            guard.discard();
+            return Err(Error::Dirty);
+        }
+        Ok(guard)
+    }
+
+    /// Whether the database was opened with [`OpenOptions::read_only`].
+    pub fn is_read_only(&self) -> bool {
+        self.shared
+            .storage
+            .as_ref()
+            .map_or(false, |storage| storage.is_read_only())
+    }
+
+    /// Whether the in-memory data diverged from the file because a save failed.
+    pub fn is_dirty(&self) -> bool {
+        self.shared
+            .storage
+            .as_ref()
+            .map_or(false, |storage| storage.dirty.load(Ordering::SeqCst))
+    }
+
+    /// Saves the whole database atomically on a blocking thread, clearing the dirty state on success.
+    pub async fn flush(&self) -> Result<(), Error> {
+        if self.shared.storage.is_none() {
+            return Ok(());
+        }
+        let shared = self.shared.clone();
+        task::spawn_blocking(move || shared.checkpoint())
+            .await
+            .map_err(join_error)?
+    }
+
+    /// Saves the database. Closing the last clone also waits until the background task stopped,
+    /// after which the file can be opened again.
+    pub async fn close(self) -> Result<(), Error> {
+        let task = match self.shared.handles.load(Ordering::SeqCst) {
+            1 => self.shared.task.lock().take(),
+            _ => None,
+        };
+        let result = self.flush().await;
+        drop(self);
+        if let Some(task) = task {
+            task.await.map_err(join_error)?;
+        }
+        result
+    }
+
+    /// Waits until the given generation of writes is saved.
+    async fn saved(&self, generation: u64) -> Result<(), Error> {
+        let mut saved = self.shared.saved.subscribe();
+        let (_, failed) = *saved
+            .wait_for(|(saved, _)| *saved >= generation)
+            .await
+            .map_err(|_| Error::Dirty)?;
+        match failed {
+            true => Err(Error::Dirty),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<T> Shared<T> {
+    /// Saves the current data, called on a blocking thread.
+    fn persist(&self) -> (u64, Result<(), Error>)
+    where
+        T: DataStore,
+    {
+        let _saving = self.saving.lock();
+        let data = self.data.blocking_read();
+        // All writes up to here are part of the saved data
+        let generation = self.generation.load(Ordering::SeqCst);
+        let result = match &self.storage {
+            Some(storage) => storage.persist(&*data),
+            None => Ok(()),
+        };
+        (generation, result)
+    }
+
+    fn checkpoint(&self) -> Result<(), Error>
+    where
+        T: DataStore,
+    {
+        let _saving = self.saving.lock();
+        let data = self.data.blocking_read();
+        let generation = self.generation.load(Ordering::SeqCst);
+        let result = match &self.storage {
+            Some(storage) => storage.checkpoint(&*data),
+            None => Ok(()),
+        };
+        self.saved.send_replace((generation, result.is_err()));
+        result
+    }
+}
+
+/// Saves the database whenever it changed, and a last time when all senders are dropped.
+async fn persist_task<T: DataStore + Send + Sync + 'static>(
+    shared: Arc<Shared<T>>,
+    mut changes: mpsc::Receiver<()>,
+) {
+    while changes.recv().await.is_some() {
+        info!("Saving database");
+        let task_shared = shared.clone();
+        match task::spawn_blocking(move || task_shared.persist()).await {
+            Ok((generation, result)) => {
+                if let Err(e) = &result {
+                    error!("Failed to save database: {e}");
+                }
+                shared.saved.send_replace((generation, result.is_err()));
+            }
+            Err(e) => error!("Failed to save database: {e}"),
+        }
+    }
+
+    info!("Saving database");
+    let result = task::spawn_blocking(move || shared.checkpoint()).await;
+    if let Err(e) = result.map_err(join_error).and_then(|r| r) {
+        error!("Failed to save database on close: {e}");
+    }
+}
+
+fn join_error(e: task::JoinError) -> Error {
+    Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e))
+}
+
+impl<T: DataStore> Clone for AsyncDatabase<T> {
+    fn clone(&self) -> Self {
+        self.shared.handles.fetch_add(1, Ordering::SeqCst);
+        Self {
+            shared: self.shared.clone(),
+            changes: self.changes.clone(),
+        }
+    }
+}
+
+impl<T: DataStore> Drop for AsyncDatabase<T> {
+    fn drop(&mut self) {
+        self.shared.handles.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<T: DataStore> fmt::Debug for AsyncDatabase<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncDatabase")
+            .field("file", &self.shared.storage.as_ref().map(|s| &s.path))
+            .finish()
+    }
+}
+
+pub struct AsyncDatabaseRead<'a, T: DataStore> {
+    data: RwLockReadGuard<'a, T>,
+}
+
+impl<'a, T: DataStore> Deref for AsyncDatabaseRead<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+pub struct AsyncDatabaseWrite<'a, T: DataStore> {
+    db: &'a AsyncDatabase<T>,
+    /// `None` once released.
+    data: Option<RwLockWriteGuard<'a, T>>,
+}
+
+impl<'a, T: DataStore + DeserializeOwned + Send + Sync + 'static> AsyncDatabaseWrite<'a, T> {
+    /// Releases the lock and waits until the changes are saved.
+    ///
+    /// Fails with [`Error::Dirty`] if saving failed, the error itself is logged,
+    /// and with [`Error::ReadOnly`] if the database was opened read-only.
+    pub async fn commit(mut self) -> Result<(), Error> {
+        let generation = self.release();
+        match self.db.changes {
+            Some(_) => self.db.saved(generation).await,
+            None if self.db.is_read_only() => Err(Error::ReadOnly),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, T: DataStore> AsyncDatabaseWrite<'a, T> {
+    /// Releases the lock without saving.
+    fn discard(mut self) {
+        self.data = None;
+    }
+
+    /// Releases the lock and notifies the background task, returning the generation of the changes.
+    fn release(&mut self) -> u64 {
+        let shared = &self.db.shared;
+        let generation = shared.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.data = None;
+        if let Some(changes) = &self.db.changes {
+            // A full channel already has a pending save, which includes these changes
+            let _ = changes.try_send(());
+        }
+        generation
+    }
+}
+
+impl<'a, T: DataStore> Deref for AsyncDatabaseWrite<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: DataStore> DerefMut for AsyncDatabaseWrite<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: DataStore> Drop for AsyncDatabaseWrite<'a, T> {
+    fn drop(&mut self) {
+        if self.data.is_some() {
+            self.release();
+        }
+    }
+}
@@ -1,33 +1,85 @@
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use serde::{de::DeserializeOwned, Serialize};
+use parking_lot::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
+use serde_json::Value;
 use std::{
-    ffi::{OsStr, OsString},
     fmt,
     fs::{self, File},
-    io::{self},
+    io::{self, Write},
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 use tracing::{error, info};
 
+use crate::{
+    backup::BackupPolicy,
+    compression::Compression,
+    error::Error,
+    format::Format,
+    journal::{self, Journal, JournalOptions},
+    lock::FileLock,
+    migration::{self, Versioned},
+    recovery::{self, RecoveryPolicy},
+    subscription::{Subscribers, Subscription},
+    watch::{ConflictPolicy, WatchEvent, WatchOptions},
+};
+
+#[cfg(feature = "derive")]
+pub use light_magic_derive::DataStore;
+
 /// This trait needs to be implemented for the Database struct.
 /// It requires a few implementations. The defined functions
 /// have default definitions.
 pub trait DataStore: Default + Serialize {
+    /// Version of the schema, which is stored in the file. See [`migration`].
+    const VERSION: u32 = 0;
+
+    /// Migrates the JSON representation of the data from version `from` to `from + 1`.
+    fn migrate(from: u32, value: Value) -> Result<Value, Error> {
+        let _ = from;
+        Ok(value)
+    }
+
     /// Opens a Database by the specified path. If the Database doesn't exist, this will create a new one! Wrap a `Arc<_>` around it to use it in parallel contexts!
-    fn open<P>(db: P) -> AtomicDatabase<Self>
+    fn open<P>(db: P) -> Result<AtomicDatabase<Self>, Error>
     where
         P: AsRef<Path>,
-        Self: DeserializeOwned,
+        Self: DeserializeOwned + Send + Sync + 'static,
+    {
+        Self::open_with(db, OpenOptions::default())
+    }
+
+    /// Opens a Database by the specified path with the given [`OpenOptions`]. If the Database doesn't exist, this will create a new one!
+    fn open_with<P>(db: P, options: OpenOptions) -> Result<AtomicDatabase<Self>, Error>
+    where
+        P: AsRef<Path>,
+        Self: DeserializeOwned + Send + Sync + 'static,
     {
         let db_path = db.as_ref();
         if db_path.exists() {
-            AtomicDatabase::load(db_path).unwrap()
+            AtomicDatabase::load_with(db_path, options)
         } else {
-            AtomicDatabase::create(db_path).unwrap()
+            AtomicDatabase::create_with(db_path, options)
         }
     }
 
+    /// Opens an existing Database read-only, without ever writing to it, see [`ReadOnlyDatabase`].
+    fn open_read_only<P>(db: P) -> Result<ReadOnlyDatabase<Self>, Error>
+    where
+        P: AsRef<Path>,
+        Self: DeserializeOwned,
+    {
+        ReadOnlyDatabase::load(db.as_ref())
+    }
+
     /// Creates a Database instance in memory. Wrap a `Arc<_>` around it to use it in parallel contexts!
     fn open_in_memory() -> AtomicDatabase<Self>
     where
@@ -37,7 +89,7 @@ pub trait DataStore: Default + Serialize {
     }
 
     /// Loads file data into the `Database`.
-    fn load(file: impl io::Read) -> std::io::Result<Self>
+    fn load(file: impl io::Read) -> Result<Self, Error>
     where
         Self: Sized,
         Self: DeserializeOwned,
@@ -45,58 +97,358 @@ pub trait DataStore: Default + Serialize {
         Ok(serde_json::from_reader(file)?)
     }
 
-    /// Saves data of the `Database` to a JSON file.
-    fn save(&self, mut file: impl io::Write) -> std::io::Result<()> {
-        serde_json::to_writer_pretty(&mut file, self)?;
-        Ok(())
+    /// Saves data of the `Database` to a JSON file, along its [`Self::VERSION`] if set.
+    fn save(&self, mut file: impl io::Write) -> Result<(), Error> {
+        let result = if Self::VERSION > 0 {
+            let versioned = Versioned {
+                version: Self::VERSION,
+                data: self,
+            };
+            serde_json::to_writer_pretty(&mut file, &versioned)
+        } else {
+            serde_json::to_writer_pretty(&mut file, self)
+        };
+        result.map_err(|e| {
+            if e.is_io() {
+                Error::Io(e.into())
+            } else {
+                Error::Encoding(e.to_string())
+            }
+        })
     }
 }
 
+/// Options for opening an [`AtomicDatabase`].
+///
+/// ```no_run
+/// use light_magic::{
+///     atomic::{DataStore, OpenOptions},
+///     journal::JournalOptions,
+///     serde::{Deserialize, Serialize},
+/// };
+///
+/// #[derive(Default, Serialize, Deserialize)]
+/// struct Database {
+///     counter: usize,
+/// }
+///
+/// impl DataStore for Database {}
+///
+/// let options = OpenOptions::new().journal(JournalOptions::new().checkpoint_records(100));
+/// let db = Database::open_with("./db.json", options).unwrap();
+/// db.write().counter += 1;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    journal: Option<JournalOptions>,
+    persistence: PersistencePolicy,
+    format: Option<Format>,
+    compression: Option<Compression>,
+    read_only: bool,
+    recovery: RecoveryPolicy,
+    watch: Option<WatchOptions>,
+    backup: Option<BackupPolicy>,
+}
+
+impl OpenOptions {
+    /// Creates the default options, which rewrite the whole file on every write.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables the append-only journal: writes only append the changes to a journal file,
+    /// which is compacted into the database file from time to time. See [`journal`].
+    pub fn journal(mut self, options: JournalOptions) -> Self {
+        self.journal = Some(options);
+        self
+    }
+
+    /// Sets when the changes of writes are saved, by default on every write.
+    pub fn persistence(mut self, policy: PersistencePolicy) -> Self {
+        self.persistence = policy;
+        self
+    }
+
+    /// Sets the format of the file, existing files in another format are converted.
+    ///
+    /// By default, existing files keep their format and new files are stored as pretty JSON.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Sets the compression of the file, existing files with another compression are converted.
+    ///
+    /// By default, existing files keep their compression and new files are not compressed.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Opens an existing database read-only, sharing the [`lock`](crate::lock) with other read-only handles.
+    ///
+    /// The file is never written, not even to convert it. Changes stay in memory and saving them
+    /// with [`AtomicDatabaseWrite::commit`] or [`AtomicDatabase::flush`] fails with [`Error::ReadOnly`].
+    /// To inspect a database, that is opened for writing by another process, use [`DataStore::open_read_only`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets how an orphaned temporary file of a crash is handled, see [`recovery`].
+    pub fn recovery(mut self, policy: RecoveryPolicy) -> Self {
+        self.recovery = policy;
+        self
+    }
+
+    /// Watches the file and reloads the data when it is changed by another program, see [`watch`](crate::watch).
+    ///
+    /// This is not supported by the [`AsyncDatabase`](crate::asynchronous::AsyncDatabase).
+    pub fn watch(mut self, options: WatchOptions) -> Self {
+        self.watch = Some(options);
+        self
+    }
+
+    /// Takes rotating backups of the database automatically, see [`backup`](crate::backup).
+    ///
+    /// This is not supported by the [`AsyncDatabase`](crate::asynchronous::AsyncDatabase).
+    pub fn backup(mut self, policy: BackupPolicy) -> Self {
+        self.backup = Some(policy);
+        self
+    }
+}
+
+/// When the changes of writes are saved, see [`OpenOptions::persistence`].
+///
+/// All policies save the database when it is dropped and on [`AtomicDatabase::flush`].
+/// [`AtomicDatabaseWrite::commit`] always saves immediately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PersistencePolicy {
+    /// Saves on every write, when the write guard is dropped.
+    #[default]
+    Immediate,
+    /// Saves in a background thread the given time after a write, coalescing all writes in between.
+    Debounced(Duration),
+    /// Saves in a background thread at the given interval, if anything was written.
+    Interval(Duration),
+    /// Saves only on [`AtomicDatabase::flush`] and when the database is dropped.
+    Manual,
+}
+
 /// Synchronized Wrapper, that automatically saves changes when path and tmp are defined.
 pub struct AtomicDatabase<T: DataStore> {
-    path: Option<PathBuf>,
-    /// Name of the DataStore temporary file.
-    tmp: Option<PathBuf>,
-    data: RwLock<T>,
+    storage: Option<Arc<Storage>>,
+    data: Arc<RwLock<T>>,
+    /// Background threads saving the changes, see [`PersistencePolicy`],
+    /// and watching the file, see [`watch`](crate::watch).
+    workers: Vec<JoinHandle<()>>,
+    subscribers: Arc<Subscribers>,
 }
 
 impl<T: DataStore + DeserializeOwned> AtomicDatabase<T> {
     /// Loads the database in memory.
     pub fn load_in_memory() -> Self {
         Self {
-            path: None,
-            tmp: None,
-            data: RwLock::new(T::default()),
+            storage: None,
+            data: Arc::new(RwLock::new(T::default())),
+            workers: Vec::new(),
+            subscribers: Arc::default(),
         }
     }
 
     /// Loads the database from the file system.
-    pub fn load(path: &Path) -> Result<Self, std::io::Error> {
-        let tmp = Self::tmp_path(path)?;
-        let file = File::open(path)?;
-        // for the future: make here version checks
-        let data = T::load(file)?;
-        atomic_write(&tmp, path, &data)?;
+    pub fn load(path: &Path) -> Result<Self, Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        Self::load_with(path, OpenOptions::default())
+    }
 
-        Ok(Self {
-            path: Some(path.into()),
-            tmp: Some(tmp),
-            data: RwLock::new(data),
-        })
+    /// Loads the database from the file system with the given options.
+    ///
+    /// Any existing journal is replayed on top of the loaded data, even if the journal mode is not enabled.
+    /// Files of an older schema version are migrated, see [`migration`].
+    ///
+    /// The database is locked until the handle is dropped, see [`lock`](crate::lock).
+    pub fn load_with(path: &Path, options: OpenOptions) -> Result<Self, Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        if options.read_only {
+            let lock = FileLock::shared(path)?;
+            let (data, format, compression) = Self::read_file(path, false, true)?;
+            let tmp = recovery::tmp_name(path);
+            let storage = Storage::new(path, tmp, format, compression, options, lock, &data)?;
+            return Self::with_storage(storage, data);
+        }
+
+        let lock = FileLock::exclusive(path)?;
+        let tmp = recovery::tmp_path(path, options.recovery, Self::validate)?;
+        let (data, format, compression) = Self::read_file(path, true, true)?;
+        let format = options.format.unwrap_or(format);
+        let compression = options.compression.unwrap_or(compression);
+        atomic_write(&tmp, path, format, compression, &data)?;
+
+        let storage = Storage::new(path, tmp, format, compression, options, lock, &data)?;
+        Self::with_storage(storage, data)
+    }
+
+    /// Converts the database file at `path` to `format`, including the changes in its journal.
+    /// The compression of the file is kept.
+    ///
+    /// The database must not be opened while converting it.
+    pub fn convert(path: &Path, format: Format) -> Result<(), Error> {
+        let _lock = FileLock::exclusive(path)?;
+        let tmp = recovery::tmp_path(path, RecoveryPolicy::Strict, Self::validate)?;
+        let (data, detected, compression) = Self::read_file(path, true, true)?;
+        atomic_write(&tmp, path, format, compression, &data)?;
+        let journal_path = journal::journal_path(path);
+        if journal_path.exists() {
+            fs::remove_file(journal_path)?;
+        }
+        info!("Converted database from {detected:?} to {format:?}");
+        Ok(())
+    }
+
+    /// Reads and migrates the database file, returning the data, the format and the compression of the file.
+    ///
+    /// Migrations keep a backup of the original file if `backup` is set.
+    /// The changes in the journal of the file are applied if `replay` is set.
+    fn read_file(
+        path: &Path,
+        backup: bool,
+        replay: bool,
+    ) -> Result<(T, Format, Compression), Error> {
+        let bytes = fs::read(path)?;
+        let (bytes, compression) = Compression::decompress(&bytes)?;
+        let format = Format::detect(&bytes)?;
+        let version = match T::VERSION {
+            0 => 0,
+            _ => format.version(&bytes)?,
+        };
+        migration::check(version, T::VERSION)?;
+        let journal_path = journal::journal_path(path);
+        let replay = replay && journal_path.exists();
+
+        let data = if version == T::VERSION {
+            let mut data = format.read::<T>(&bytes)?;
+            if replay {
+                let mut value = journal::to_value(&data)?;
+                if Self::replay(&journal_path, &mut value)? {
+                    data = serde_json::from_value(value)?;
+                }
+            }
+            data
+        } else if format.is_binary() {
+            return Err(Error::Migration(format!(
+                "files in the {format:?} format can't be migrated from version {version}, \
+                 convert them to JSON first"
+            )));
+        } else {
+            if backup {
+                migration::backup(path, version)?;
+            }
+            let mut value: Value = serde_json::from_slice(&bytes)?;
+            if let Value::Object(object) = &mut value {
+                object.remove(migration::VERSION_KEY);
+            }
+            // The journal was written by the previous version
+            if replay {
+                Self::replay(&journal_path, &mut value)?;
+            }
+            let value = migration::migrate(version, T::VERSION, value, T::migrate)?;
+            serde_json::from_value(value)?
+        };
+        Ok((data, format, compression))
+    }
+
+    /// Checks whether the bytes are a complete and deserializable database file, see [`recovery`].
+    fn validate(bytes: &[u8]) -> Result<(), Error> {
+        let (bytes, _) = Compression::decompress(bytes)?;
+        let format = Format::detect(&bytes)?;
+        let version = match T::VERSION {
+            0 => 0,
+            _ => format.version(&bytes)?,
+        };
+        migration::check(version, T::VERSION)?;
+        if version == T::VERSION {
+            format.read::<T>(&bytes)?;
+        } else {
+            serde_json::from_slice::<IgnoredAny>(&bytes)?;
+        }
+        Ok(())
     }
 
     /// Creates a new database and save it.
-    pub fn create(path: &Path) -> Result<Self, std::io::Error> {
-        let tmp = Self::tmp_path(path)?;
+    pub fn create(path: &Path) -> Result<Self, Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        Self::create_with(path, OpenOptions::default())
+    }
+
+    /// Creates a new database with the given options and save it.
+    ///
+    /// Fails with [`Error::ReadOnly`] if the options are [`OpenOptions::read_only`].
+    pub fn create_with(path: &Path, options: OpenOptions) -> Result<Self, Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        if options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let lock = FileLock::exclusive(path)?;
+        let tmp = recovery::tmp_path(path, options.recovery, Self::validate)?;
 
         let data = Default::default();
-        atomic_write(&tmp, path, &data)?;
+        let format = options.format.unwrap_or(Format::Json);
+        let compression = options.compression.unwrap_or_default();
+        atomic_write(&tmp, path, format, compression, &data)?;
 
-        Ok(Self {
-            path: Some(path.into()),
-            tmp: Some(tmp),
-            data: RwLock::new(data),
-        })
+        let storage = Storage::new(path, tmp, format, compression, options, lock, &data)?;
+        Self::with_storage(storage, data)
+    }
+
+    /// Starts the background threads of the persistence policy, watching and backups, if needed.
+    fn with_storage(storage: Storage, data: T) -> Result<Self, Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        let storage = Arc::new(storage);
+        // Dropping the database on errors stops the threads, that were already started
+        let mut db = Self {
+            storage: Some(storage.clone()),
+            data: Arc::new(RwLock::new(data)),
+            workers: Vec::new(),
+            subscribers: Arc::default(),
+        };
+        match storage.policy {
+            _ if storage.is_read_only() => {}
+            PersistencePolicy::Debounced(_) | PersistencePolicy::Interval(_) => {
+                let (storage, data) = (storage.clone(), db.data.clone());
+                let worker = thread::Builder::new()
+                    .name("light-magic-persistence".into())
+                    .spawn(move || storage.run(&data))?;
+                db.workers.push(worker);
+            }
+            PersistencePolicy::Immediate | PersistencePolicy::Manual => {}
+        }
+        if storage.watch.is_some() {
+            let (storage, data) = (storage.clone(), db.data.clone());
+            let subscribers = db.subscribers.clone();
+            let watcher = thread::Builder::new()
+                .name("light-magic-watcher".into())
+                .spawn(move || storage.watch_file(&data, &subscribers))?;
+            db.workers.push(watcher);
+        }
+        if storage.backup.as_ref().and_then(|b| b.interval).is_some() {
+            let (storage, data) = (storage.clone(), db.data.clone());
+            let worker = thread::Builder::new()
+                .name("light-magic-backup".into())
+                .spawn(move || storage.run_backups(&data))?;
+            db.workers.push(worker);
+        }
+        Ok(db)
     }
 
     /// Locks the database for reading.
@@ -106,42 +458,661 @@ impl<T: DataStore + DeserializeOwned> AtomicDatabase<T> {
         }
     }
 
-    /// Locks the database for writing. This will save the changes atomically on drop.
+    /// Locks the database for writing. This will save the changes atomically on drop,
+    /// or later depending on the [`PersistencePolicy`].
+    ///
+    /// Errors on saving are only logged, use [`AtomicDatabaseWrite::commit`] to handle them.
     pub fn write(&self) -> AtomicDatabaseWrite<'_, T> {
         AtomicDatabaseWrite {
-            path: self.path.as_deref(),
-            tmp: self.tmp.as_deref(),
+            storage: self.storage.as_deref(),
+            subscribers: &self.subscribers,
             data: self.data.write(),
         }
     }
 
-    fn tmp_path(path: &Path) -> Result<PathBuf, std::io::Error> {
-        let mut tmp_name = OsString::from(".");
-        tmp_name.push(path.file_name().unwrap_or(OsStr::new("db")));
-        tmp_name.push("~");
-        let tmp = path.with_file_name(tmp_name);
-        if tmp.exists() {
-            error!(
-                "Found orphaned database temporary file '{tmp:?}'. \
-                 The server has recently crashed or is already running. \
-                 Delete this before continuing!"
-            );
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::AlreadyExists,
-                "orphaned temporary file exists",
-            ));
+    /// Locks the database for writing, failing with [`Error::Dirty`] if a previous save failed
+    /// and with [`Error::ReadOnly`] if it was opened read-only.
+    pub fn try_write(&self) -> Result<AtomicDatabaseWrite<'_, T>, Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let mut guard = self.write();
+        if self.is_dirty() {
+            guard.storage = None;
+            return Err(Error::Dirty);
+        }
+        Ok(guard)
+    }
+
+    /// Writes a consistent snapshot of the database atomically to `path`, while readers continue.
+    ///
+    /// The snapshot has the format and compression of the database file and can be opened like it.
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let (format, compression) = match &self.storage {
+            Some(storage) => (storage.format, storage.compression),
+            None => (Format::Json, Compression::None),
+        };
+        let data = self.data.read();
+        atomic_write(&recovery::tmp_name(path), path, format, compression, &*data)?;
+        info!("Saved backup of the database to '{path:?}'");
+        Ok(())
+    }
+
+    /// Whether the database was opened with [`OpenOptions::read_only`].
+    pub fn is_read_only(&self) -> bool {
+        self.storage
+            .as_ref()
+            .map_or(false, |storage| storage.is_read_only())
+    }
+
+    /// Whether the in-memory data diverged from the file because a save failed.
+    pub fn is_dirty(&self) -> bool {
+        self.storage
+            .as_ref()
+            .map_or(false, |storage| storage.dirty.load(Ordering::SeqCst))
+    }
+
+    /// Saves the whole database atomically, clearing the dirty state on success.
+    pub fn flush(&self) -> Result<(), Error> {
+        match &self.storage {
+            Some(storage) => storage.checkpoint(&*self.data.read()),
+            None => Ok(()),
+        }
+    }
+
+    /// Subscribes to the changes of all tables, which are sent once a write guard is released.
+    /// See [`subscription`](crate::subscription).
+    pub fn subscribe(&self) -> Result<Subscription, Error> {
+        self.subscribers.subscribe(None, &*self.data.read())
+    }
+
+    /// Subscribes to the changes of the table, that is the field `table` of the database.
+    pub fn subscribe_table(&self, table: &str) -> Result<Subscription, Error> {
+        self.subscribers.subscribe(Some(table), &*self.data.read())
+    }
+
+    /// Begins a transaction. Changes are only saved on [`AtomicDatabaseTransaction::commit`],
+    /// otherwise the previous state is restored.
+    pub fn begin(&self) -> AtomicDatabaseTransaction<'_, T>
+    where
+        T: Clone,
+    {
+        let guard = self.write();
+        let backup = guard.data.clone();
+        AtomicDatabaseTransaction {
+            guard,
+            backup: Some(backup),
+        }
+    }
+
+    /// Runs `f` in a transaction, which is committed if `f` returns `Ok`.
+    /// On `Err`, a panic or a failed save, the previous state is restored.
+    ///
+    /// ```
+    /// use light_magic::{
+    ///     atomic::DataStore,
+    ///     serde::{Deserialize, Serialize},
+    ///     Error,
+    /// };
+    ///
+    /// #[derive(Default, Clone, Serialize, Deserialize)]
+    /// struct Database {
+    ///     balance: i64,
+    ///     history: Vec<i64>,
+    /// }
+    ///
+    /// impl DataStore for Database {}
+    ///
+    /// #[derive(Debug)]
+    /// enum PaymentError {
+    ///     InsufficientBalance,
+    ///     Database(Error),
+    /// }
+    ///
+    /// impl From<Error> for PaymentError {
+    ///     fn from(e: Error) -> Self {
+    ///         PaymentError::Database(e)
+    ///     }
+    /// }
+    ///
+    /// let db = Database::open_in_memory();
+    /// let result = db.transaction(|db| {
+    ///     db.history.push(-100);
+    ///     db.balance -= 100;
+    ///     if db.balance < 0 {
+    ///         return Err(PaymentError::InsufficientBalance);
+    ///     }
+    ///     Ok(())
+    /// });
+    /// assert!(result.is_err());
+    /// assert!(db.read().history.is_empty());
+    /// ```
+    pub fn transaction<R, E, F>(&self, f: F) -> Result<R, E>
+    where
+        T: Clone,
+        E: From<Error>,
+        F: FnOnce(&mut T) -> Result<R, E>,
+    {
+        let mut tx = self.begin();
+        let value = f(&mut tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// Splits the database into its storage and data, without saving it.
+    #[cfg(feature = "async")]
+    pub(crate) fn into_parts(mut self) -> (Option<Storage>, T) {
+        self.stop_workers();
+        let storage = self.storage.take().map(|storage| {
+            Arc::try_unwrap(storage)
+                .ok()
+                .expect("the storage is only shared with the workers")
+        });
+        (storage, std::mem::take(&mut *self.data.write()))
+    }
+
+    /// Replays the journal on top of `value`, returning whether any records were applied.
+    fn replay(journal_path: &Path, value: &mut Value) -> Result<bool, Error> {
+        let applied = journal::replay(journal_path, value)?;
+        if applied > 0 {
+            info!("Replayed {applied} journal records");
+        }
+        Ok(applied > 0)
+    }
+}
+
+/// Read-only handle of a database file, which has no `write` method, see [`DataStore::open_read_only`].
+///
+/// It never writes any file and doesn't take the [`lock`](crate::lock), so it can inspect a database,
+/// that is opened by another process. Its saves are atomic, so a complete snapshot is always loaded.
+/// Use [`ReadOnlyDatabase::reload`] to pick up changes.
+///
+/// ```compile_fail
+/// # use light_magic::{atomic::DataStore, serde::{Deserialize, Serialize}};
+/// # #[derive(Default, Serialize, Deserialize)]
+/// # struct Database {
+/// #     counter: usize,
+/// # }
+/// # impl DataStore for Database {}
+/// let db = Database::open_read_only("./db.json").unwrap();
+/// db.write().counter += 1;
+/// ```
+pub struct ReadOnlyDatabase<T: DataStore> {
+    path: PathBuf,
+    data: RwLock<T>,
+    /// Files of the loaded snapshot.
+    stamp: Mutex<Stamp>,
+}
+
+impl<T: DataStore + DeserializeOwned> ReadOnlyDatabase<T> {
+    /// Loads the database from the file system, including the changes in its journal.
+    ///
+    /// Files of an older schema version are only migrated in memory.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let stamp = Stamp::new(path);
+        let (data, _, _) = AtomicDatabase::<T>::read_file(path, false, true)?;
+        Ok(Self {
+            path: path.into(),
+            data: RwLock::new(data),
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    /// Locks the database for reading.
+    pub fn read(&self) -> AtomicDatabaseRead<'_, T> {
+        AtomicDatabaseRead {
+            data: self.data.read(),
+        }
+    }
+
+    /// Reloads the database if its file or journal changed since it was loaded, returning whether it did.
+    ///
+    /// If loading fails, the previous data is kept.
+    pub fn reload(&self) -> Result<bool, Error> {
+        let mut stamp = self.stamp.lock();
+        let current = Stamp::new(&self.path);
+        if current == *stamp {
+            return Ok(false);
+        }
+        let (data, _, _) = AtomicDatabase::<T>::read_file(&self.path, false, true)?;
+        *self.data.write() = data;
+        *stamp = current;
+        Ok(true)
+    }
+
+    /// Path of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<T: DataStore> fmt::Debug for ReadOnlyDatabase<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadOnlyDatabase")
+            .field("file", &self.path)
+            .finish()
+    }
+}
+
+/// Modification times and lengths of the database file and its journal, to detect changes.
+#[derive(Debug, PartialEq, Eq)]
+struct Stamp {
+    file: Option<(SystemTime, u64)>,
+    journal: Option<(SystemTime, u64)>,
+}
+
+impl Stamp {
+    fn new(path: &Path) -> Self {
+        Self {
+            file: file_stamp(path),
+            journal: file_stamp(&journal::journal_path(path)),
+        }
+    }
+}
+
+/// Modification time and length of a file, `None` if it doesn't exist.
+pub(crate) fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// File system backend of a persistent database.
+pub(crate) struct Storage {
+    pub(crate) path: PathBuf,
+    /// Name of the DataStore temporary file.
+    tmp: PathBuf,
+    format: Format,
+    compression: Compression,
+    journal: Option<Mutex<Journal>>,
+    /// Set if the last save failed.
+    pub(crate) dirty: AtomicBool,
+    policy: PersistencePolicy,
+    schedule: Mutex<Schedule>,
+    /// Notifies the background thread about changes of the schedule.
+    wakeup: Condvar,
+    /// Held until the storage is dropped, shared if read-only.
+    lock: FileLock,
+    watch: Option<WatchOptions>,
+    /// Modification time and size of the file after the last save, to detect external changes.
+    stamp: Mutex<Option<(SystemTime, u64)>>,
+    backup: Option<BackupPolicy>,
+    /// Writes since the last backup of [`BackupPolicy::commits`].
+    commits: AtomicU64,
+}
+
+/// State of the background threads of a [`PersistencePolicy`] and of [`watch`](crate::watch).
+#[derive(Default)]
+struct Schedule {
+    /// Changes that are not saved yet.
+    pending: bool,
+    /// The file system notified about a change of the file.
+    modified: bool,
+    stop: bool,
+}
+
+impl Storage {
+    /// Expects `data` to be already saved at `path`.
+    fn new<T: DataStore>(
+        path: &Path,
+        tmp: PathBuf,
+        format: Format,
+        compression: Compression,
+        options: OpenOptions,
+        lock: FileLock,
+        data: &T,
+    ) -> Result<Self, Error> {
+        let journal_path = journal::journal_path(path);
+        let journal = match options.journal {
+            // The journal was already replayed and is left as is
+            _ if lock.is_shared() => None,
+            Some(options) => {
+                let snapshot = journal::to_value(data)?;
+                Some(Mutex::new(Journal::create(
+                    &journal_path,
+                    options,
+                    snapshot,
+                )?))
+            }
+            None => {
+                if journal_path.exists() {
+                    fs::remove_file(&journal_path)?;
+                }
+                None
+            }
+        };
+        Ok(Self {
+            path: path.into(),
+            tmp,
+            format,
+            compression,
+            journal,
+            dirty: AtomicBool::new(false),
+            policy: options.persistence,
+            schedule: Mutex::new(Schedule::default()),
+            wakeup: Condvar::new(),
+            lock,
+            watch: options.watch,
+            stamp: Mutex::new(file_stamp(path)),
+            backup: options.backup,
+            commits: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether the database was opened read-only, so it is never saved.
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.lock.is_shared()
+    }
+
+    /// Saves the changes of a write according to the policy.
+    fn changed<T: DataStore>(&self, data: &T) {
+        if self.is_read_only() {
+            return;
+        }
+        match self.policy {
+            PersistencePolicy::Immediate => {
+                info!("Saving database");
+                if let Err(e) = self.persist(data) {
+                    error!("Failed to save database: {}", e);
+                }
+            }
+            _ => {
+                self.schedule.lock().pending = true;
+                self.wakeup.notify_all();
+            }
+        }
+        self.written(data);
+    }
+
+    /// Saves the changes of a write right away, see [`AtomicDatabaseWrite::commit`].
+    fn commit<T: DataStore>(&self, data: &T) -> Result<(), Error> {
+        self.persist(data)?;
+        self.written(data);
+        Ok(())
+    }
+
+    /// Takes a backup if the write completes the [`BackupPolicy::commits`].
+    fn written<T: DataStore>(&self, data: &T) {
+        let Some(policy) = &self.backup else {
+            return;
+        };
+        let Some(commits) = policy.commits else {
+            return;
+        };
+        if self.commits.fetch_add(1, Ordering::SeqCst) + 1 >= commits {
+            self.commits.store(0, Ordering::SeqCst);
+            if let Err(e) = self.take_backup(policy, data) {
+                error!("Failed to back up database: {e}");
+            }
+        }
+    }
+
+    /// Takes a backup of the policy, deleting the oldest ones.
+    fn take_backup<T: DataStore>(&self, policy: &BackupPolicy, data: &T) -> Result<PathBuf, Error> {
+        policy.rotate(&self.path, |tmp, backup| {
+            atomic_write(tmp, backup, self.format, self.compression, data)
+        })
+    }
+
+    /// Takes backups at the [`BackupPolicy::interval`] until [`Storage::stop`].
+    fn run_backups<T: DataStore>(&self, data: &RwLock<T>) {
+        let Some(policy) = &self.backup else {
+            return;
+        };
+        let Some(interval) = policy.interval else {
+            return;
+        };
+        loop {
+            let mut schedule = self.schedule.lock();
+            let deadline = Instant::now() + interval;
+            while !schedule.stop && !self.wakeup.wait_until(&mut schedule, deadline).timed_out() {}
+            if schedule.stop {
+                return;
+            }
+            drop(schedule);
+            if let Err(e) = self.take_backup(policy, &*data.read()) {
+                error!("Failed to back up database: {e}");
+            }
+        }
+    }
+
+    /// Persists the changes, either by appending them to the journal or by rewriting the file.
+    pub(crate) fn persist<T: DataStore>(&self, data: &T) -> Result<(), Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        self.schedule.lock().pending = false;
+        let result = match &self.journal {
+            Some(journal) => self.append(&mut journal.lock(), data),
+            None => self.write_file(data),
+        };
+        self.saved(&result);
+        result
+    }
+
+    /// Updates the state after a save, a failed one is retried by the background thread.
+    fn saved(&self, result: &Result<(), Error>) {
+        self.dirty.store(result.is_err(), Ordering::SeqCst);
+        if result.is_err() {
+            self.schedule.lock().pending = true;
+        }
+    }
+
+    /// Whether the database is dirty and has unsaved changes, see [`Storage::restore_state`].
+    fn save_state(&self) -> (bool, bool) {
+        (
+            self.dirty.load(Ordering::SeqCst),
+            self.schedule.lock().pending,
+        )
+    }
+
+    /// Restores the state before a failed save, whose changes were rolled back.
+    fn restore_state(&self, (dirty, pending): (bool, bool)) {
+        self.dirty.store(dirty, Ordering::SeqCst);
+        self.schedule.lock().pending = pending;
+    }
+
+    /// Runs the background thread of the policy until [`Storage::stop`].
+    fn run<T: DataStore>(&self, data: &RwLock<T>) {
+        loop {
+            let mut schedule = self.schedule.lock();
+            let delay = match self.policy {
+                PersistencePolicy::Debounced(delay) => {
+                    while !schedule.pending && !schedule.stop {
+                        self.wakeup.wait(&mut schedule);
+                    }
+                    delay
+                }
+                PersistencePolicy::Interval(interval) => interval,
+                PersistencePolicy::Immediate | PersistencePolicy::Manual => return,
+            };
+            // Collect the writes until the deadline
+            let deadline = Instant::now() + delay;
+            while !schedule.stop && !self.wakeup.wait_until(&mut schedule, deadline).timed_out() {}
+            // Saving the remaining changes is up to the database
+            if schedule.stop {
+                return;
+            }
+            if schedule.pending {
+                drop(schedule);
+                info!("Saving database");
+                if let Err(e) = self.persist(&*data.read()) {
+                    error!("Failed to save database: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Stops the background thread.
+    fn stop(&self) {
+        self.schedule.lock().stop = true;
+        self.wakeup.notify_all();
+    }
+
+    fn append<T: DataStore>(&self, journal: &mut Journal, data: &T) -> Result<(), Error> {
+        journal.append(journal::to_value(data)?)?;
+        if journal.needs_checkpoint() {
+            info!("Compacting database journal");
+            // The changes are already durable in the journal
+            if let Err(e) = self.write_file(data).and_then(|_| journal.reset()) {
+                error!("Failed to compact database journal: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the full database file and clears the journal.
+    pub(crate) fn checkpoint<T: DataStore>(&self, data: &T) -> Result<(), Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        self.schedule.lock().pending = false;
+        let result = self.write_snapshot(data);
+        self.saved(&result);
+        result
+    }
+
+    fn write_snapshot<T: DataStore>(&self, data: &T) -> Result<(), Error> {
+        self.write_file(data)?;
+        if let Some(journal) = &self.journal {
+            let mut journal = journal.lock();
+            journal.reset()?;
+            journal.set_snapshot(journal::to_value(data)?);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the database file, remembering it as the last save.
+    fn write_file<T: DataStore>(&self, data: &T) -> Result<(), Error> {
+        atomic_write(&self.tmp, &self.path, self.format, self.compression, data)?;
+        *self.stamp.lock() = file_stamp(&self.path);
+        Ok(())
+    }
+
+    /// Watches the file for external changes until [`Storage::stop`], see [`watch`](crate::watch).
+    fn watch_file<T: DataStore + DeserializeOwned>(
+        self: &Arc<Self>,
+        data: &RwLock<T>,
+        subscribers: &Subscribers,
+    ) {
+        let Some(options) = &self.watch else {
+            return;
+        };
+        #[cfg(feature = "notify")]
+        let _watcher = {
+            let storage = Arc::downgrade(self);
+            crate::watch::notify(&self.path, move || {
+                if let Some(storage) = storage.upgrade() {
+                    storage.schedule.lock().modified = true;
+                    storage.wakeup.notify_all();
+                }
+            })
+        };
+        loop {
+            let mut schedule = self.schedule.lock();
+            let deadline = Instant::now() + options.interval;
+            while !schedule.stop
+                && !schedule.modified
+                && !self.wakeup.wait_until(&mut schedule, deadline).timed_out()
+            {}
+            if schedule.stop {
+                return;
+            }
+            schedule.modified = false;
+            drop(schedule);
+            if file_stamp(&self.path) == *self.stamp.lock() {
+                continue;
+            }
+            let event = self.reload(data, subscribers, options.conflict);
+            if let (Some(event), Some(callback)) = (event, &options.callback) {
+                callback(&event);
+            }
+        }
+    }
+
+    /// Reloads the externally changed file, resolving conflicts with unsaved changes by the policy.
+    ///
+    /// Returns `None` if the file was not changed externally.
+    fn reload<T: DataStore + DeserializeOwned>(
+        &self,
+        data: &RwLock<T>,
+        subscribers: &Subscribers,
+        conflict: ConflictPolicy,
+    ) -> Option<WatchEvent> {
+        let mut data = data.write();
+        // Saves hold a lock on the data, so the change was no save in progress
+        let stamp = file_stamp(&self.path);
+        if stamp == *self.stamp.lock() {
+            return None;
+        }
+        // Changes only written to the own journal are not in the edited file
+        let journaled = self
+            .journal
+            .as_ref()
+            .map_or(false, |j| !j.lock().is_empty());
+        let unsaved =
+            journaled || self.schedule.lock().pending || self.dirty.load(Ordering::SeqCst);
+        if unsaved && conflict == ConflictPolicy::PreferMemory {
+            info!("Database file changed on disk, keeping the unsaved changes");
+            return Some(match self.checkpoint(&*data) {
+                Ok(()) => WatchEvent::Conflict(conflict),
+                Err(e) => WatchEvent::Failed(e),
+            });
         }
-        Ok(tmp)
+
+        info!("Database file changed on disk, reloading it");
+        // Don't retry until the file changes again
+        *self.stamp.lock() = stamp;
+        // The own journal refers to the previous file, read-only handles apply the one of the writer
+        let reloaded = match AtomicDatabase::<T>::read_file(
+            &self.path,
+            !self.is_read_only(),
+            self.journal.is_none(),
+        ) {
+            Ok((reloaded, _, _)) => reloaded,
+            Err(e) => {
+                error!("Failed to reload database: {e}");
+                return Some(WatchEvent::Failed(e));
+            }
+        };
+        *data = reloaded;
+        subscribers.publish(&*data);
+        let result = match self.journal {
+            // The journal records refer to the previous file
+            Some(_) => self.checkpoint(&*data),
+            None => {
+                self.schedule.lock().pending = false;
+                self.dirty.store(false, Ordering::SeqCst);
+                Ok(())
+            }
+        };
+        Some(match result {
+            Ok(()) if unsaved => WatchEvent::Conflict(conflict),
+            Ok(()) => WatchEvent::Reloaded,
+            Err(e) => WatchEvent::Failed(e),
+        })
     }
 }
 
 /// Atomic write routine, loosely inspired by the tempfile crate.
 ///
 /// This assumes that the rename FS operation is atomic.
-fn atomic_write<T: DataStore>(tmp: &Path, path: &Path, data: &T) -> Result<(), std::io::Error> {
+fn atomic_write<T: DataStore>(
+    tmp: &Path,
+    path: &Path,
+    format: Format,
+    compression: Compression,
+    data: &T,
+) -> Result<(), Error> {
     {
         let mut tmpfile = File::create(tmp)?;
-        data.save(&mut tmpfile)?;
+        if compression == Compression::None {
+            format.write(data, &mut tmpfile)?;
+        } else {
+            let mut bytes = Vec::new();
+            format.write(data, &mut bytes)?;
+            tmpfile.write_all(&compression.compress(bytes)?)?;
+        }
         tmpfile.sync_all()?; // just to be sure!
     }
     fs::rename(tmp, path)?;
@@ -151,17 +1122,36 @@ fn atomic_write<T: DataStore>(tmp: &Path, path: &Path, data: &T) -> Result<(), s
 impl<T: DataStore> fmt::Debug for AtomicDatabase<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("AtomicDatabase")
-            .field("file", &self.path)
+            .field("file", &self.storage.as_ref().map(|s| &s.path))
             .finish()
     }
 }
 
+impl<T: DataStore> AtomicDatabase<T> {
+    /// Stops the background threads, which don't save the pending changes.
+    fn stop_workers(&mut self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        if self.workers.is_empty() {
+            return;
+        }
+        storage.stop();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("A background thread of the database panicked");
+            }
+        }
+    }
+}
+
 impl<T: DataStore> Drop for AtomicDatabase<T> {
     fn drop(&mut self) {
-        if let (Some(tmp), Some(path)) = (&self.tmp, &self.path) {
+        self.stop_workers();
+        if let Some(storage) = self.storage.as_ref().filter(|s| !s.is_read_only()) {
             info!("Saving database");
             let guard = self.data.read();
-            if let Err(e) = atomic_write(tmp, path, &*guard) {
+            if let Err(e) = storage.checkpoint(&*guard) {
                 error!("Failed to save database on drop: {}", e);
             }
         }
@@ -180,11 +1170,24 @@ impl<'a, T: DataStore> Deref for AtomicDatabaseRead<'a, T> {
 }
 
 pub struct AtomicDatabaseWrite<'a, T: DataStore> {
-    tmp: Option<&'a Path>,
-    path: Option<&'a Path>,
+    storage: Option<&'a Storage>,
+    subscribers: &'a Subscribers,
     data: RwLockWriteGuard<'a, T>,
 }
 
+impl<'a, T: DataStore> AtomicDatabaseWrite<'a, T> {
+    /// Saves the changes atomically, returning the error instead of only logging it.
+    /// The changes are saved right away, regardless of the [`PersistencePolicy`].
+    ///
+    /// If this fails, the database is marked as dirty, see [`AtomicDatabase::is_dirty`].
+    pub fn commit(mut self) -> Result<(), Error> {
+        match self.storage.take() {
+            Some(storage) => storage.commit(&*self.data),
+            None => Ok(()),
+        }
+    }
+}
+
 impl<'a, T: DataStore> Deref for AtomicDatabaseWrite<'a, T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
@@ -200,11 +1203,65 @@ impl<'a, T: DataStore> DerefMut for AtomicDatabaseWrite<'a, T> {
 
 impl<'a, T: DataStore> Drop for AtomicDatabaseWrite<'a, T> {
     fn drop(&mut self) {
-        if let (Some(tmp), Some(path)) = (self.tmp, self.path) {
-            info!("Saving database");
-            if let Err(e) = atomic_write(tmp, path, &*self.data) {
-                error!("Failed to save database: {}", e);
-            }
+        if let Some(storage) = self.storage {
+            storage.changed(&*self.data);
+        }
+        self.subscribers.publish(&*self.data);
+    }
+}
+
+/// Write guard of a transaction, see [`AtomicDatabase::begin`].
+///
+/// Dropping it without calling [`commit`](Self::commit), also due to a panic, rolls the changes back.
+pub struct AtomicDatabaseTransaction<'a, T: DataStore> {
+    guard: AtomicDatabaseWrite<'a, T>,
+    /// State before the transaction, `None` once committed.
+    backup: Option<T>,
+}
+
+impl<'a, T: DataStore> AtomicDatabaseTransaction<'a, T> {
+    /// Commits the changes and saves them atomically.
+    ///
+    /// If saving fails, the changes are rolled back and the error is returned.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let backup = self.backup.take();
+        let Some(storage) = self.guard.storage.take() else {
+            return Ok(());
+        };
+        let state = storage.save_state();
+        let result = storage.commit(&*self.guard.data);
+        if let (Err(_), Some(backup)) = (&result, backup) {
+            // The data matches the file again, so there is nothing left to save
+            *self.guard.data = backup;
+            storage.restore_state(state);
+        }
+        result
+    }
+
+    /// Discards the changes, restoring the state before the transaction.
+    pub fn rollback(self) {}
+}
+
+impl<'a, T: DataStore> Deref for AtomicDatabaseTransaction<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T: DataStore> DerefMut for AtomicDatabaseTransaction<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: DataStore> Drop for AtomicDatabaseTransaction<'a, T> {
+    fn drop(&mut self) {
+        if let Some(backup) = self.backup.take() {
+            info!("Rolling back transaction");
+            *self.guard.data = backup;
+            // nothing changed, so there is nothing to save
+            self.guard.storage = None;
         }
     }
 }
@@ -0,0 +1,204 @@
+//! Backups of live databases.
+//!
+//! [`AtomicDatabase::backup_to`](crate::atomic::AtomicDatabase::backup_to) writes a consistent
+//! snapshot of the database atomically to another file, while readers continue. With a
+//! [`BackupPolicy`], see [`OpenOptions::backup`](crate::atomic::OpenOptions::backup) and
+//! `EncryptedOpenOptions::backup`, backups are taken automatically at an interval or every few
+//! commits, encrypted databases only support the latter. Their names are timestamped, like `db.json.20240618T205450.123Z.bak`, and only the
+//! latest ones are kept.
+//!
+//! ```no_run
+//! use light_magic::{
+//!     atomic::{DataStore, OpenOptions},
+//!     backup::BackupPolicy,
+//!     serde::{Deserialize, Serialize},
+//! };
+//! use std::time::Duration;
+//!
+//! #[derive(Default, Serialize, Deserialize)]
+//! struct Database {
+//!     counter: usize,
+//! }
+//!
+//! impl DataStore for Database {}
+//!
+//! let policy = BackupPolicy::new()
+//!     .dir("./backups")
+//!     .keep(24)
+//!     .interval(Duration::from_secs(60 * 60));
+//! let db = Database::open_with("./db.json", OpenOptions::new().backup(policy)).unwrap();
+//! db.backup_to("./db.manual.json").unwrap();
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+use crate::{error::Error, recovery};
+
+/// Rotating backups of a database, see [`backup`](self).
+#[derive(Debug, Clone)]
+pub struct BackupPolicy {
+    dir: Option<PathBuf>,
+    keep: usize,
+    pub(crate) interval: Option<Duration>,
+    pub(crate) commits: Option<u64>,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            keep: 7,
+            interval: None,
+            commits: None,
+        }
+    }
+}
+
+impl BackupPolicy {
+    /// Creates the default policy, which keeps the last 7 backups next to the database file.
+    ///
+    /// Backups are only taken, once an [`interval`](Self::interval) or a number of
+    /// [`commits`](Self::commits) is set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory of the backups, which is created if necessary.
+    pub fn dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dir = Some(dir.as_ref().into());
+        self
+    }
+
+    /// Sets the number of backups to keep, older ones are deleted. `0` keeps all backups.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Takes a backup in a background thread at the given interval.
+    ///
+    /// Not supported by encrypted databases, which have no background threads.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Takes a backup every `commits` writes, right after the write.
+    pub fn commits(mut self, commits: u64) -> Self {
+        self.commits = Some(commits.max(1));
+        self
+    }
+
+    /// Writes a new backup of the database at `path` with `write` and deletes the oldest ones.
+    ///
+    /// `write` is called with the temporary file and the backup file.
+    pub(crate) fn rotate(
+        &self,
+        path: &Path,
+        write: impl FnOnce(&Path, &Path) -> Result<(), Error>,
+    ) -> Result<PathBuf, Error> {
+        let dir = match (&self.dir, path.parent()) {
+            (Some(dir), _) => dir.as_path(),
+            (None, Some(parent)) if !parent.as_os_str().is_empty() => parent,
+            (None, _) => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        let name = path.file_name().unwrap_or("db".as_ref()).to_string_lossy();
+
+        let mut time = SystemTime::now();
+        let backup = loop {
+            let backup = dir.join(format!("{name}.{}.bak", timestamp(time)));
+            if !backup.exists() {
+                break backup;
+            }
+            // Keeps the order of backups within the same millisecond
+            time += Duration::from_millis(1);
+        };
+        write(&recovery::tmp_name(&backup), &backup)?;
+        info!("Saved backup of the database to '{backup:?}'");
+
+        if self.keep > 0 {
+            let mut backups = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let file_name = entry?.file_name();
+                let is_backup = file_name
+                    .to_str()
+                    .and_then(|f| f.strip_prefix(&*name)?.strip_prefix('.'))
+                    .and_then(|f| f.strip_suffix(".bak"))
+                    .map_or(false, is_timestamp);
+                if is_backup {
+                    backups.push(file_name);
+                }
+            }
+            // The timestamps are ordered like their names
+            backups.sort();
+            for old in &backups[..backups.len().saturating_sub(self.keep)] {
+                fs::remove_file(dir.join(old))?;
+            }
+        }
+        Ok(backup)
+    }
+}
+
+/// Formats the time like `20240618T205450.123Z` in UTC.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Date of the days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}.{:03}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Whether the string is formatted by [`timestamp`].
+fn is_timestamp(s: &str) -> bool {
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    s.len() == 20
+        && s.is_char_boundary(19)
+        && digits(&s[..8])
+        && &s[8..9] == "T"
+        && digits(&s[9..15])
+        && &s[15..16] == "."
+        && digits(&s[16..19])
+        && &s[19..] == "Z"
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_timestamp, timestamp};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn timestamps() {
+        let time = UNIX_EPOCH + Duration::from_millis(1718744090123);
+        assert_eq!(timestamp(time), "20240618T205450.123Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(timestamp(leap_day), "20000229T000000.000Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101T000000.000Z");
+
+        assert!(is_timestamp(&timestamp(time)));
+        assert!(!is_timestamp("v1"));
+        assert!(!is_timestamp("20240618T205450.123X"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Compression of persisted database files.
+//!
+//! The files of an [`AtomicDatabase`](crate::atomic::AtomicDatabase) are compressed as a whole,
+//! so they are plain zstd or gzip streams, which can also be decompressed with the usual tools.
+//! Encrypted databases compress the plaintext before encrypting it, marked by a short prefix.
+//!
+//! The compression is detected when loading a file, so existing files keep their compression
+//! unless another one is selected with [`OpenOptions::compression`](crate::atomic::OpenOptions::compression).
+
+use std::borrow::Cow;
+
+use crate::error::Error;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Prefix of compressed plaintext in an encrypted file, followed by the compressed stream.
+#[cfg(feature = "encrypted")]
+const PLAINTEXT_MAGIC: [u8; 8] = *b"LMCOMPRS";
+
+/// Compression of a database file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// No compression, the default.
+    #[default]
+    None,
+    /// zstd with the given level from `1` to `22`, `0` selects the default level `3`.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// gzip with the given level from `0` to `9`.
+    #[cfg(feature = "gzip")]
+    Gzip(u32),
+}
+
+impl Compression {
+    /// Detects the compression of the contents of a file.
+    ///
+    /// The level is not stored in the file, so the default level of the algorithm is returned.
+    pub fn detect(bytes: &[u8]) -> Result<Self, Error> {
+        let feature = if bytes.starts_with(&ZSTD_MAGIC) {
+            "zstd"
+        } else if bytes.starts_with(&GZIP_MAGIC) {
+            "gzip"
+        } else {
+            return Ok(Compression::None);
+        };
+        match feature {
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd(0)),
+            #[cfg(feature = "gzip")]
+            "gzip" => Ok(Compression::Gzip(6)),
+            feature => Err(Error::Encoding(format!(
+                "the file is compressed with {feature}, which requires the `{feature}` feature"
+            ))),
+        }
+    }
+
+    /// Compresses the bytes.
+    pub(crate) fn compress(self, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(bytes),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Ok(zstd::encode_all(&bytes[..], level)?),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(level) => {
+                use std::io::Write;
+                let level = flate2::Compression::new(level);
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(&bytes)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    /// Decompresses the bytes, detecting their compression.
+    pub(crate) fn decompress(bytes: &[u8]) -> Result<(Cow<'_, [u8]>, Self), Error> {
+        let compression = Self::detect(bytes)?;
+        let bytes = match compression {
+            Compression::None => Cow::Borrowed(bytes),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => Cow::Owned(zstd::decode_all(bytes)?),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(_) => {
+                use std::io::Read;
+                let mut decompressed = Vec::new();
+                flate2::read::GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+                Cow::Owned(decompressed)
+            }
+        };
+        Ok((bytes, compression))
+    }
+
+    /// Compresses the plaintext of an encrypted file, prefixing it if compressed.
+    #[cfg(feature = "encrypted")]
+    pub(crate) fn compress_plaintext(self, plaintext: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if self == Compression::None {
+            return Ok(plaintext);
+        }
+        let mut prefixed = PLAINTEXT_MAGIC.to_vec();
+        prefixed.extend_from_slice(&self.compress(plaintext)?);
+        Ok(prefixed)
+    }
+
+    /// Decompresses the plaintext of an encrypted file, detecting its compression.
+    #[cfg(feature = "encrypted")]
+    pub(crate) fn decompress_plaintext(plaintext: Vec<u8>) -> Result<(Vec<u8>, Self), Error> {
+        let Some(compressed) = plaintext.strip_prefix(&PLAINTEXT_MAGIC[..]) else {
+            return Ok((plaintext, Compression::None));
+        };
+        let (decompressed, compression) = Self::decompress(compressed)?;
+        if compression == Compression::None {
+            return Err(Error::Encoding(
+                "unknown compression of the plaintext".into(),
+            ));
+        }
+        Ok((decompressed.into_owned(), compression))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Compression;
+
+    fn compressions() -> Vec<Compression> {
+        vec![
+            Compression::None,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(0),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(6),
+        ]
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = br#"{"users":{"0":{"name":"Nils"},"1":{"name":"Nils"}}}"#.repeat(100);
+        for compression in compressions() {
+            let compressed = compression.compress(data.clone()).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 10, "{compression:?}");
+            }
+            let (decompressed, detected) = Compression::decompress(&compressed).unwrap();
+            assert_eq!(detected, compression);
+            assert_eq!(decompressed, &data[..]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "encrypted")]
+    fn plaintext_roundtrip() {
+        let data = b"LMSCHEMA\x01\x00\x00\x00data".repeat(10);
+        for compression in compressions() {
+            let compressed = compression.compress_plaintext(data.clone()).unwrap();
+            let (decompressed, detected) = Compression::decompress_plaintext(compressed).unwrap();
+            assert_eq!(detected, compression);
+            assert_eq!(decompressed, data);
+        }
+    }
+}
@@ -1,60 +1,331 @@
+pub use aes_gcm::{Aes256Gcm, Key};
+
 use aes_gcm::{
-    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
-    Aes256Gcm, Key, Nonce,
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    Nonce,
 };
-use argon2::{self, Argon2};
 use bincode::{
     self,
-    serde::{decode_from_slice, decode_from_std_read, encode_into_std_write, encode_to_vec},
+    serde::{decode_from_slice, encode_into_std_write, encode_to_vec},
 };
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    ffi::{OsStr, OsString},
     fmt,
     fs::{self, File},
     io::{self, Read, Write},
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::SystemTime,
 };
 use tracing::{error, info};
 use zeroize::Zeroize;
 
-const SALT_LEN: usize = 16;
-const NONCE_LEN: usize = 12;
+use crate::{
+    atomic::file_stamp,
+    backup::BackupPolicy,
+    compression::Compression,
+    error::Error,
+    header::{self, Header, HeaderV1, HeaderV2, KdfParams, KeyMode, KeySlot},
+    lock::FileLock,
+    migration,
+    recovery::{self, RecoveryPolicy},
+};
+
+#[cfg(feature = "derive")]
+pub use light_magic_derive::EncryptedDataStore;
+
+pub(crate) const SALT_LEN: usize = 16;
+pub(crate) const NONCE_LEN: usize = 12;
 
 #[inline]
 pub fn bincode_cfg() -> impl bincode::config::Config {
     bincode::config::standard().with_fixed_int_encoding()
 }
 
-/// Structure to hold encrypted data along with salt and nonce.
+/// Encodes the data in the binary format of the encrypted database, e.g. in [`EncryptedDataStore::migrate`].
+pub fn encode<D: Serialize>(data: &D) -> Result<Vec<u8>, Error> {
+    encode_to_vec(data, bincode_cfg()).map_err(|e| Error::Encoding(format!("Encoding failed: {e}")))
+}
+
+/// Decodes data in the binary format of the encrypted database, e.g. in [`EncryptedDataStore::migrate`].
+pub fn decode<D: DeserializeOwned>(bytes: &[u8]) -> Result<D, Error> {
+    let (data, _) = decode_from_slice(bytes, bincode_cfg())
+        .map_err(|e| Error::Encoding(format!("Failed to decode decrypted data: {e}")))?;
+    Ok(data)
+}
+
+/// Secret to open an encrypted database with, which has to match the [`KeyMode`] of the file.
+#[derive(Clone, Copy)]
+pub enum Secret<'a> {
+    /// A password, from which the key is derived.
+    Password(&'a str),
+    /// A raw 256-bit key.
+    Key(&'a Key<Aes256Gcm>),
+}
+
+impl<'a> fmt::Debug for Secret<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the secret itself
+        match self {
+            Secret::Password(_) => f.write_str("Password(..)"),
+            Secret::Key(_) => f.write_str("Key(..)"),
+        }
+    }
+}
+
+/// Generates a new random key, e.g. to be stored in a keyfile.
+pub fn generate_key() -> Key<Aes256Gcm> {
+    Aes256Gcm::generate_key(OsRng)
+}
+
+/// Reads a raw 256-bit key from a keyfile, which contains exactly the 32 bytes of the key.
+pub fn read_keyfile<P: AsRef<Path>>(path: P) -> Result<Key<Aes256Gcm>, Error> {
+    let mut bytes = fs::read(path)?;
+    if bytes.len() != 32 {
+        bytes.zeroize();
+        return Err(Error::Encoding(
+            "A keyfile has to contain exactly 32 bytes".into(),
+        ));
+    }
+    let key = *Key::<Aes256Gcm>::from_slice(&bytes);
+    bytes.zeroize(); // wipe heap buffer
+    Ok(key)
+}
+
+/// Label of the key slot of a new database.
+const DEFAULT_SLOT: &str = "default";
+
+/// Structure to hold encrypted data along with its header and nonce.
 #[derive(Serialize, Deserialize)]
 pub struct EncryptedData {
+    header: Header,
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+    /// Set if read from a file of a previous format, which has no key slots.
+    #[serde(skip)]
+    previous: Option<PreviousFormat>,
+}
+
+/// Key and associated data of a file of a previous format.
+struct PreviousFormat {
+    key_mode: KeyMode,
+    salt: [u8; SALT_LEN],
+    aad: Vec<u8>,
+}
+
+/// Encrypted data of format version 1.
+#[derive(Deserialize)]
+struct EncryptedDataV1 {
+    header: HeaderV1,
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+/// Encrypted data of format version 2.
+#[derive(Deserialize)]
+struct EncryptedDataV2 {
+    header: HeaderV2,
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+/// Encrypted data of the format without header.
+#[derive(Deserialize)]
+struct LegacyEncryptedData {
     salt: [u8; SALT_LEN],
     nonce: [u8; NONCE_LEN],
     ciphertext: Vec<u8>,
 }
 
+impl EncryptedData {
+    /// Decodes the encrypted data of both the current and the previous formats.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let map_err = |e| Error::Encoding(format!("Failed to decode encrypted data: {e}"));
+        if !bytes.starts_with(&header::MAGIC) {
+            let (legacy, _): (LegacyEncryptedData, usize) =
+                decode_from_slice(bytes, bincode_cfg()).map_err(map_err)?;
+            return Ok(Self {
+                header: Header::new(Vec::new()),
+                nonce: legacy.nonce,
+                ciphertext: legacy.ciphertext,
+                previous: Some(PreviousFormat {
+                    key_mode: KeyMode::Password(KdfParams::default()),
+                    salt: legacy.salt,
+                    // The legacy format has no header to authenticate
+                    aad: Vec::new(),
+                }),
+            });
+        }
+
+        let version = bytes
+            .get(header::MAGIC.len()..header::MAGIC.len() + 2)
+            .map(|v| u16::from_le_bytes([v[0], v[1]]));
+        match version {
+            Some(1) => {
+                let (v1, _): (EncryptedDataV1, usize) =
+                    decode_from_slice(bytes, bincode_cfg()).map_err(map_err)?;
+                return Ok(Self {
+                    header: Header::new(Vec::new()),
+                    nonce: v1.nonce,
+                    ciphertext: v1.ciphertext,
+                    previous: Some(PreviousFormat {
+                        key_mode: KeyMode::Password(v1.header.kdf),
+                        salt: v1.header.salt,
+                        aad: encode(&v1.header)?,
+                    }),
+                });
+            }
+            Some(2) => {
+                let (v2, _): (EncryptedDataV2, usize) =
+                    decode_from_slice(bytes, bincode_cfg()).map_err(map_err)?;
+                return Ok(Self {
+                    header: Header::new(Vec::new()),
+                    nonce: v2.nonce,
+                    ciphertext: v2.ciphertext,
+                    previous: Some(PreviousFormat {
+                        key_mode: v2.header.key_mode,
+                        salt: v2.header.salt,
+                        aad: encode(&v2.header)?,
+                    }),
+                });
+            }
+            _ => {}
+        }
+
+        let (encrypted, _): (Self, usize) =
+            decode_from_slice(bytes, bincode_cfg()).map_err(map_err)?;
+        encrypted.header.check()?;
+        Ok(encrypted)
+    }
+
+    /// Returns the data key and the id of the key slot it was unwrapped with.
+    fn unlock(&self, secret: Secret) -> Result<(u32, Key<Aes256Gcm>), Error> {
+        match &self.previous {
+            Some(previous) => Ok((0, previous.key_mode.key(secret, &previous.salt)?)),
+            None => self.header.unlock(secret),
+        }
+    }
+
+    /// Upgrades a previous format to a new data key wrapped by a single key slot.
+    fn upgrade(
+        &self,
+        secret: Secret,
+        key: Key<Aes256Gcm>,
+    ) -> Result<(Header, Key<Aes256Gcm>), Error> {
+        let Some(previous) = &self.previous else {
+            return Ok((self.header.clone(), key));
+        };
+        info!(
+            "Upgrading encrypted file to format version {}",
+            header::FORMAT_VERSION
+        );
+        let kdf = match previous.key_mode {
+            KeyMode::Password(kdf) => kdf,
+            KeyMode::Key => KdfParams::default(),
+        };
+        let data_key = generate_key();
+        let slot = KeySlot::wrap(0, DEFAULT_SLOT, secret, kdf, &data_key)?;
+        Ok((Header::new(vec![slot]), data_key))
+    }
+
+    /// Header of the encrypted data.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
 /// This trait needs to be implemented for the Database struct.
 /// It requires a few implementations. The defined functions
 /// have default implementations.
 pub trait EncryptedDataStore: Default + Serialize {
+    /// Version of the schema, which is stored in the file. See [`migration`].
+    const VERSION: u32 = 0;
+
+    /// Migrates the binary representation of the data from version `from` to `from + 1`.
+    ///
+    /// Use [`decode`] with the previous and [`encode`] with the next version of the data types.
+    fn migrate(from: u32, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let _ = from;
+        Ok(data)
+    }
+
     /// Opens a Database by the specified path and password. If the Database doesn't exist,
     /// this will create a new one! Wrap a `Arc<_>` around it to use it in parallel contexts!
-    fn open<P>(db: P, password: &str) -> io::Result<EncryptedAtomicDatabase<Self>>
+    fn open<P>(db: P, password: &str) -> Result<EncryptedAtomicDatabase<Self>, Error>
+    where
+        P: AsRef<Path>,
+        Self: DeserializeOwned,
+    {
+        Self::open_with(db, password, EncryptedOpenOptions::default())
+    }
+
+    /// Opens a Database by the specified path and password with the given [`EncryptedOpenOptions`].
+    /// If the Database doesn't exist, this will create a new one!
+    fn open_with<P>(
+        db: P,
+        password: &str,
+        options: EncryptedOpenOptions,
+    ) -> Result<EncryptedAtomicDatabase<Self>, Error>
+    where
+        P: AsRef<Path>,
+        Self: DeserializeOwned,
+    {
+        Self::open_with_secret(db, Secret::Password(password), options)
+    }
+
+    /// Opens a Database by the specified path with a raw key instead of a password.
+    /// If the Database doesn't exist, this will create a new one!
+    fn open_with_key<P>(db: P, key: &Key<Aes256Gcm>) -> Result<EncryptedAtomicDatabase<Self>, Error>
+    where
+        P: AsRef<Path>,
+        Self: DeserializeOwned,
+    {
+        Self::open_with_secret(db, Secret::Key(key), EncryptedOpenOptions::default())
+    }
+
+    /// Opens a Database by the specified path with the raw key stored in a keyfile, see [`read_keyfile`].
+    /// If the Database doesn't exist, this will create a new one!
+    fn open_with_keyfile<P, K>(db: P, keyfile: K) -> Result<EncryptedAtomicDatabase<Self>, Error>
+    where
+        P: AsRef<Path>,
+        K: AsRef<Path>,
+        Self: DeserializeOwned,
+    {
+        let key = read_keyfile(keyfile)?;
+        Self::open_with_key(db, &key)
+    }
+
+    /// Opens a Database by the specified path with any [`Secret`] and the given [`EncryptedOpenOptions`].
+    /// If the Database doesn't exist, this will create a new one!
+    fn open_with_secret<P>(
+        db: P,
+        secret: Secret,
+        options: EncryptedOpenOptions,
+    ) -> Result<EncryptedAtomicDatabase<Self>, Error>
     where
         P: AsRef<Path>,
         Self: DeserializeOwned,
     {
         let db_path = db.as_ref();
         if db_path.exists() {
-            EncryptedAtomicDatabase::load(db_path, password)
+            EncryptedAtomicDatabase::load_with_options(db_path, secret, options)
         } else {
-            EncryptedAtomicDatabase::create_new(db_path, password)
+            EncryptedAtomicDatabase::create_with_secret(db_path, secret, options)
         }
     }
 
+    /// Opens an existing Database read-only with the provided password, without ever writing to it.
+    /// See [`EncryptedReadOnlyDatabase`].
+    fn open_read_only<P>(db: P, password: &str) -> Result<EncryptedReadOnlyDatabase<Self>, Error>
+    where
+        P: AsRef<Path>,
+        Self: DeserializeOwned,
+    {
+        EncryptedReadOnlyDatabase::load_with_secret(db, Secret::Password(password))
+    }
+
     // Loads the database from a string with the provided password and save it to the filesystem.
     // It checks if the provided password can decrypt the content successfully before saving it.
     // Errors when a file already exists at the provided path.
@@ -62,7 +333,7 @@ pub trait EncryptedDataStore: Default + Serialize {
         data: &str,
         path: P,
         password: &str,
-    ) -> io::Result<EncryptedAtomicDatabase<Self>>
+    ) -> Result<EncryptedAtomicDatabase<Self>, Error>
     where
         P: AsRef<Path>,
         Self: DeserializeOwned,
@@ -71,25 +342,21 @@ pub trait EncryptedDataStore: Default + Serialize {
         if !db_path.exists() {
             EncryptedAtomicDatabase::create_from_str(data, path, password)
         } else {
-            Err(io::Error::new(
+            Err(Error::Io(io::Error::new(
                 io::ErrorKind::AlreadyExists,
                 "A file already exists at the provided path!",
-            ))
+            )))
         }
     }
 
     /// Loads the database after decrypting it from file.
-    fn load_encrypted(file: &mut impl Read, key: &Key<Aes256Gcm>) -> io::Result<Self>
+    fn load_encrypted(file: &mut impl Read, key: &Key<Aes256Gcm>) -> Result<Self, Error>
     where
         Self: DeserializeOwned,
     {
-        let encrypted: EncryptedData = decode_from_std_read(file, bincode_cfg()).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to decode encrypted data: {e}"),
-            )
-        })?;
-        Self::decrypt(&encrypted, key)
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Self::decrypt(&EncryptedData::from_bytes(&bytes)?, key)
     }
 
     /// Encrypts and safes the database to the file.
@@ -97,79 +364,178 @@ pub trait EncryptedDataStore: Default + Serialize {
         &self,
         mut file: impl Write,
         key: &Key<Aes256Gcm>,
-        salt: [u8; SALT_LEN],
-    ) -> io::Result<usize> {
-        let encrypted = self.encrypt(key, salt)?;
-        encode_into_std_write(encrypted, &mut file, bincode_cfg()).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to write encrypted data to file: {e}"),
-            )
-        })
+        header: &Header,
+    ) -> Result<usize, Error> {
+        let encrypted = self.encrypt(key, header)?;
+        write_encrypted(&mut file, &encrypted)
+    }
+
+    /// Encrypts the current data and returns the encrypted data, authenticating the `header`.
+    fn encrypt(&self, key: &Key<Aes256Gcm>, header: &Header) -> Result<EncryptedData, Error> {
+        self.encrypt_with(key, header, Compression::None)
     }
 
-    /// Encrypts the current data and returns the encrypted data.
-    fn encrypt(&self, key: &Key<Aes256Gcm>, salt: [u8; SALT_LEN]) -> io::Result<EncryptedData> {
+    /// Encrypts the current data like [`Self::encrypt`], compressing the plaintext before.
+    fn encrypt_with(
+        &self,
+        key: &Key<Aes256Gcm>,
+        header: &Header,
+        compression: Compression,
+    ) -> Result<EncryptedData, Error> {
         // Non-allocating nonce
         let mut nonce = [0u8; NONCE_LEN];
         OsRng.fill_bytes(&mut nonce);
 
         // Encode plaintext
-        let plaintext = encode_to_vec(self, bincode_cfg()).map_err(|e| {
-            io::Error::new(io::ErrorKind::InvalidData, format!("Encoding failed: {e}"))
-        })?;
+        let plaintext = migration::encode_version(Self::VERSION, encode(self)?);
+        let plaintext = compression.compress_plaintext(plaintext)?;
 
         let cipher = Aes256Gcm::new(key);
+        let aad = header.aad()?;
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &aad,
+        };
         let ct = cipher
-            .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Encryption failed: {e}")))?;
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::Encryption)?;
 
         Ok(EncryptedData {
-            salt,
+            header: header.clone(),
             nonce,
             ciphertext: ct,
+            previous: None,
         })
     }
 
-    /// Decrypts the encrypted data using the given key and returns the decrypted data.
-    fn decrypt(encrypted: &EncryptedData, key: &Key<Aes256Gcm>) -> io::Result<Self>
+    /// Decrypts the encrypted data using the given key and returns the decrypted data,
+    /// migrated to [`Self::VERSION`].
+    fn decrypt(encrypted: &EncryptedData, key: &Key<Aes256Gcm>) -> Result<Self, Error>
     where
         Self: DeserializeOwned,
     {
-        let cipher = Aes256Gcm::new(key);
-        let pt = cipher
-            .decrypt(
-                Nonce::from_slice(&encrypted.nonce),
-                encrypted.ciphertext.as_ref(),
-            )
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Decryption failed: Incorrect password or corrupted data. {e}"),
-                )
-            })?;
-
-        let (data, _) = decode_from_slice(&pt, bincode_cfg()).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to decode decrypted data: {e}"),
-            )
-        })?;
+        decode_versioned(&decrypt_plaintext(encrypted, key)?.0)
+    }
+}
 
-        Ok(data)
+/// Writes the encrypted data to the file.
+fn write_encrypted(mut file: impl Write, encrypted: &EncryptedData) -> Result<usize, Error> {
+    encode_into_std_write(encrypted, &mut file, bincode_cfg()).map_err(|e| match e {
+        bincode::error::EncodeError::Io { inner, .. } => Error::Io(inner),
+        e => Error::Encoding(format!("Failed to write encrypted data to file: {e}")),
+    })
+}
+
+/// Decrypts and decompresses the plaintext, returning it with its compression.
+fn decrypt_plaintext(
+    encrypted: &EncryptedData,
+    key: &Key<Aes256Gcm>,
+) -> Result<(Vec<u8>, Compression), Error> {
+    let cipher = Aes256Gcm::new(key);
+    let aad = match &encrypted.previous {
+        Some(previous) => previous.aad.clone(),
+        None => encrypted.header.aad()?,
+    };
+    let payload = Payload {
+        msg: &encrypted.ciphertext,
+        aad: &aad,
+    };
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&encrypted.nonce), payload)
+        .map_err(|_| Error::Decryption)?;
+    Compression::decompress_plaintext(plaintext)
+}
+
+/// Decodes the plaintext, migrating it from its schema version if necessary.
+fn decode_versioned<T: EncryptedDataStore + DeserializeOwned>(
+    plaintext: &[u8],
+) -> Result<T, Error> {
+    let (version, data) = migration::decode_version(plaintext);
+    migration::check(version, T::VERSION)?;
+    if version == T::VERSION {
+        decode(data)
+    } else {
+        let data = migration::migrate(version, T::VERSION, data.to_vec(), T::migrate)?;
+        decode(&data)
     }
 }
 
-/// Derive a 32-byte key from the password and salt using Argon2id.
-fn derive_key(password: &str, salt: &[u8]) -> io::Result<Key<Aes256Gcm>> {
-    let mut key = [0u8; 32];
-    Argon2::default()
-        .hash_password_into(password.as_bytes(), salt, &mut key)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Key derivation failed"))?;
+/// Options for creating an [`EncryptedAtomicDatabase`].
+///
+/// The key derivation parameters are stored in the file, existing databases are always
+/// opened with their stored parameters. Use [`EncryptedAtomicDatabase::rehash_kdf`] to change them.
+///
+/// ```no_run
+/// use light_magic::{
+///     encrypted::{EncryptedDataStore, EncryptedOpenOptions},
+///     header::KdfParams,
+///     serde::{Deserialize, Serialize},
+/// };
+///
+/// #[derive(Default, Serialize, Deserialize)]
+/// struct Database {
+///     counter: usize,
+/// }
+///
+/// impl EncryptedDataStore for Database {}
+///
+/// let options = EncryptedOpenOptions::new().kdf(KdfParams::new().memory_cost(64 * 1024));
+/// let db = Database::open_with("./db.enc", "password", options).unwrap();
+/// db.write().counter += 1;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EncryptedOpenOptions {
+    kdf: KdfParams,
+    compression: Compression,
+    recovery: RecoveryPolicy,
+    read_only: bool,
+    backup: Option<BackupPolicy>,
+}
+
+impl EncryptedOpenOptions {
+    /// Creates the default options, using the default Argon2id parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the parameters of the key derivation for new password-based databases.
+    pub fn kdf(mut self, kdf: KdfParams) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    /// Sets the compression of the plaintext for new databases, see [`crate::compression`].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets how an orphaned temporary file of a crash is handled, see [`recovery`].
+    pub fn recovery(mut self, policy: RecoveryPolicy) -> Self {
+        self.recovery = policy;
+        self
+    }
+
+    /// Opens an existing database read-only, sharing the [`lock`](crate::lock) with other read-only handles.
+    ///
+    /// The file is never written, not even to upgrade or migrate it. Changes stay in memory and saving
+    /// them with [`EncryptedAtomicDatabaseWrite::commit`] or [`EncryptedAtomicDatabase::flush`] fails
+    /// with [`Error::ReadOnly`]. To inspect a database, that is opened for writing by another process,
+    /// use [`EncryptedDataStore::open_read_only`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
 
-    let out = *Key::<Aes256Gcm>::from_slice(&key);
-    key.zeroize(); // wipe stack buffer
-    Ok(out)
+    /// Takes rotating backups of the database automatically, see [`backup`](crate::backup).
+    ///
+    /// As the encrypted database has no background threads, only backups every few
+    /// [`BackupPolicy::commits`] are supported, opening it with a [`BackupPolicy::interval`] fails.
+    /// The backups are encrypted with the same data key, so they open with the same passwords.
+    pub fn backup(mut self, policy: BackupPolicy) -> Self {
+        self.backup = Some(policy);
+        self
+    }
 }
 
 /// Synchronized Wrapper, that automatically saves changes when path and tmp are defined
@@ -177,34 +543,124 @@ pub struct EncryptedAtomicDatabase<T: EncryptedDataStore> {
     path: PathBuf,
     tmp: PathBuf,
     data: RwLock<T>,
+    /// The data key, unwrapped by the key slot `slot`.
     key: RwLock<Key<Aes256Gcm>>,
-    salt: RwLock<[u8; SALT_LEN]>,
+    header: RwLock<Header>,
+    slot: AtomicU32,
+    compression: RwLock<Compression>,
+    /// Set if the last save failed.
+    dirty: AtomicBool,
+    /// Lock of the file, shared if read-only, see [`lock`](crate::lock).
+    lock: FileLock,
+    backup: Option<Backups>,
+}
+
+/// State of the [`EncryptedOpenOptions::backup`] policy.
+struct Backups {
+    policy: BackupPolicy,
+    /// Writes since the last backup.
+    commits: AtomicU64,
+}
+
+impl Backups {
+    /// Fails if the policy has a [`BackupPolicy::interval`], which would need a background thread.
+    fn new(policy: Option<BackupPolicy>) -> Result<Option<Self>, Error> {
+        let Some(policy) = policy else {
+            return Ok(None);
+        };
+        if policy.interval.is_some() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Backups at an interval are not supported by encrypted databases",
+            )));
+        }
+        Ok(Some(Self {
+            policy,
+            commits: AtomicU64::new(0),
+        }))
+    }
+
+    /// Takes a backup if the write completes the [`BackupPolicy::commits`], deleting the oldest ones.
+    fn written<T: EncryptedDataStore>(
+        &self,
+        path: &Path,
+        data: &T,
+        key: &Key<Aes256Gcm>,
+        header: &Header,
+        compression: Compression,
+    ) {
+        let Some(commits) = self.policy.commits else {
+            return;
+        };
+        if self.commits.fetch_add(1, Ordering::SeqCst) + 1 < commits {
+            return;
+        }
+        self.commits.store(0, Ordering::SeqCst);
+        let result = self.policy.rotate(path, |tmp, backup| {
+            atomic_write_encrypted(tmp, backup, data, key, header, compression)
+        });
+        if let Err(e) = result {
+            error!("Failed to back up database: {e}");
+        }
+    }
 }
 
 impl<T: EncryptedDataStore + DeserializeOwned> EncryptedAtomicDatabase<T> {
     /// Loads the database with the provided password.
-    pub fn load<P: AsRef<Path>>(path: P, password: &str) -> io::Result<Self> {
+    pub fn load<P: AsRef<Path>>(path: P, password: &str) -> Result<Self, Error> {
+        Self::load_with_secret(path, Secret::Password(password))
+    }
+
+    /// Loads the database with the provided secret, which has to match one of the key slots of the file.
+    pub fn load_with_secret<P: AsRef<Path>>(path: P, secret: Secret) -> Result<Self, Error> {
+        Self::load_with_options(path, secret, EncryptedOpenOptions::default())
+    }
+
+    /// Loads the database with the provided secret and options.
+    ///
+    /// The database is locked until the handle is dropped, see [`lock`](crate::lock).
+    pub fn load_with_options<P: AsRef<Path>>(
+        path: P,
+        secret: Secret,
+        options: EncryptedOpenOptions,
+    ) -> Result<Self, Error> {
         let new_path = path.as_ref().to_path_buf();
-        let tmp = Self::tmp_path(&new_path)?;
+        let backup = Backups::new(options.backup)?;
+        let (lock, tmp) = if options.read_only {
+            (FileLock::shared(&new_path)?, recovery::tmp_name(&new_path))
+        } else {
+            let lock = FileLock::exclusive(&new_path)?;
+            let tmp = recovery::tmp_path(&new_path, options.recovery, |b| validate(b, secret))?;
+            (lock, tmp)
+        };
 
         // Reads the whole envelope once; don't reopen
-        let mut file = File::open(&new_path)?;
-        let encrypted: EncryptedData =
-            decode_from_std_read(&mut file, bincode_cfg()).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Failed to decode encrypted data: {e}"),
-                )
-            })?;
-        let key = derive_key(password, &encrypted.salt)?;
-        let data = T::decrypt(&encrypted, &key)?;
+        let encrypted = EncryptedData::from_bytes(&fs::read(&new_path)?)?;
+        let (slot, key) = encrypted.unlock(secret)?;
+        let (plaintext, compression) = decrypt_plaintext(&encrypted, &key)?;
+        let (version, _) = migration::decode_version(&plaintext);
+        migration::check(version, T::VERSION)?;
+        if version != T::VERSION && !options.read_only {
+            migration::backup(&new_path, version)?;
+        }
+        let data = decode_versioned(&plaintext)?;
+        // Read-only handles upgrade files of a previous format only in memory
+        let (header, key) = encrypted.upgrade(secret, key)?;
+        if (version != T::VERSION || encrypted.previous.is_some()) && !options.read_only {
+            atomic_write_encrypted(&tmp, &new_path, &data, &key, &header, compression)?;
+        }
 
         Ok(Self {
             path: new_path,
             tmp,
             data: RwLock::new(data),
             key: RwLock::new(key),
-            salt: RwLock::new(encrypted.salt),
+            header: RwLock::new(header),
+            slot: AtomicU32::new(slot),
+            compression: RwLock::new(compression),
+            dirty: AtomicBool::new(false),
+            lock,
+            backup,
         })
     }
 
@@ -214,50 +670,90 @@ impl<T: EncryptedDataStore + DeserializeOwned> EncryptedAtomicDatabase<T> {
         data: &str,
         path: P,
         password: &str,
-    ) -> io::Result<Self> {
+    ) -> Result<Self, Error> {
         let new_path = path.as_ref().to_path_buf();
-        let tmp = Self::tmp_path(&new_path)?;
-
-        let (encrypted, _): (EncryptedData, usize) =
-            decode_from_slice(data.as_bytes(), bincode_cfg()).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Failed to decode encrypted data: {e}"),
-                )
-            })?;
-        let key = derive_key(password, &encrypted.salt)?;
+        let lock = FileLock::exclusive(&new_path)?;
+        let secret = Secret::Password(password);
+        let tmp = recovery::tmp_path(&new_path, RecoveryPolicy::Strict, |b| validate(b, secret))?;
+
+        let encrypted = EncryptedData::from_bytes(data.as_bytes())?;
+        let (slot, key) = encrypted.unlock(secret)?;
         let data = T::decrypt(&encrypted, &key)?;
+        let (header, key) = encrypted.upgrade(secret, key)?;
+        let compression = Compression::None;
 
-        atomic_write_encrypted(&tmp, &new_path, &data, &key, encrypted.salt)?;
+        atomic_write_encrypted(&tmp, &new_path, &data, &key, &header, compression)?;
 
         Ok(Self {
             path: new_path,
             tmp,
             data: RwLock::new(data),
             key: RwLock::new(key),
-            salt: RwLock::new(encrypted.salt),
+            header: RwLock::new(header),
+            slot: AtomicU32::new(slot),
+            compression: RwLock::new(compression),
+            dirty: AtomicBool::new(false),
+            lock,
+            backup: None,
         })
     }
 
     /// Creates a new database and save it with the provided password.
-    pub fn create_new<P: AsRef<Path>>(path: P, password: &str) -> io::Result<Self> {
+    pub fn create_new<P: AsRef<Path>>(path: P, password: &str) -> Result<Self, Error> {
+        Self::create_with(path, password, EncryptedOpenOptions::default())
+    }
+
+    /// Creates a new database and save it with the provided password and options.
+    pub fn create_with<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+        options: EncryptedOpenOptions,
+    ) -> Result<Self, Error> {
+        Self::create_with_secret(path, Secret::Password(password), options)
+    }
+
+    /// Creates a new database and save it with the provided secret and options.
+    /// The data key is wrapped by a single key slot for the secret.
+    ///
+    /// Fails with [`Error::ReadOnly`] if the options are [`EncryptedOpenOptions::read_only`].
+    pub fn create_with_secret<P: AsRef<Path>>(
+        path: P,
+        secret: Secret,
+        options: EncryptedOpenOptions,
+    ) -> Result<Self, Error> {
+        if options.read_only {
+            return Err(Error::ReadOnly);
+        }
         let new_path = path.as_ref().to_path_buf();
-        let tmp = Self::tmp_path(&new_path)?;
+        options.kdf.validate()?;
+        let backup = Backups::new(options.backup)?;
+        let lock = FileLock::exclusive(&new_path)?;
+        let tmp = recovery::tmp_path(&new_path, options.recovery, |b| validate(b, secret))?;
 
-        // Generate salt
-        let mut salt_bytes = [0u8; SALT_LEN];
-        OsRng.fill_bytes(&mut salt_bytes);
-        let key = derive_key(password, &salt_bytes)?;
+        let key = generate_key();
+        let header = Header::new(vec![KeySlot::wrap(
+            0,
+            DEFAULT_SLOT,
+            secret,
+            options.kdf,
+            &key,
+        )?]);
 
         let data = Default::default();
-        atomic_write_encrypted(&tmp, &new_path, &data, &key, salt_bytes)?;
+        let compression = options.compression;
+        atomic_write_encrypted(&tmp, &new_path, &data, &key, &header, compression)?;
 
         Ok(Self {
             path: new_path,
             tmp,
             data: RwLock::new(data),
             key: RwLock::new(key),
-            salt: RwLock::new(salt_bytes),
+            header: RwLock::new(header),
+            slot: AtomicU32::new(0),
+            compression: RwLock::new(compression),
+            dirty: AtomicBool::new(false),
+            lock,
+            backup,
         })
     }
 
@@ -269,69 +765,329 @@ impl<T: EncryptedDataStore + DeserializeOwned> EncryptedAtomicDatabase<T> {
     }
 
     /// Locks the database for writing. Saves changes atomically on drop.
+    ///
+    /// Errors on saving are only logged, use [`EncryptedAtomicDatabaseWrite::commit`] to handle them.
     pub fn write(&self) -> EncryptedAtomicDatabaseWrite<'_, T> {
+        // Lock the data first, the key slots are only changed while holding it
+        let data = self.data.write();
         let key = *self.key.read();
-        let salt = *self.salt.read();
+        let header = self.header.read().clone();
         EncryptedAtomicDatabaseWrite {
             path: self.path.as_ref(),
             tmp: self.tmp.as_ref(),
-            data: self.data.write(),
+            data,
             key,
-            salt,
+            header,
+            compression: *self.compression.read(),
+            save: true,
+            read_only: self.is_read_only(),
+            dirty: &self.dirty,
+            backup: self.backup.as_ref(),
+        }
+    }
+
+    /// Locks the database for writing, failing with [`Error::Dirty`] if a previous save failed
+    /// and with [`Error::ReadOnly`] if it was opened read-only.
+    pub fn try_write(&self) -> Result<EncryptedAtomicDatabaseWrite<'_, T>, Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let mut guard = self.write();
+        if self.is_dirty() {
+            guard.save = false;
+            return Err(Error::Dirty);
+        }
+        Ok(guard)
+    }
+
+    /// Whether the database was opened with [`EncryptedOpenOptions::read_only`].
+    pub fn is_read_only(&self) -> bool {
+        self.lock.is_shared()
+    }
+
+    /// Whether the in-memory data diverged from the file because a save failed.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// Saves the whole database atomically, clearing the dirty state on success.
+    pub fn flush(&self) -> Result<(), Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
         }
+        let data_guard = self.data.read();
+        let key = self.key.read();
+        let header = self.header.read();
+        let compression = *self.compression.read();
+        let result = atomic_write_encrypted(
+            &self.tmp,
+            &self.path,
+            &*data_guard,
+            &key,
+            &header,
+            compression,
+        );
+        self.dirty.store(result.is_err(), Ordering::SeqCst);
+        result
     }
 
-    /// Changes the password of the database. This will re-encrypt the data with a new key derived from the new password.
-    pub fn change_password(&self, new_password: &str) -> io::Result<()> {
+    /// Writes a consistent snapshot of the database atomically to `path`, while readers continue.
+    ///
+    /// The snapshot is encrypted with the same data key, so it opens with the same passwords.
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
         let data_guard = self.data.read();
+        let key = self.key.read();
+        let header = self.header.read();
+        let compression = *self.compression.read();
+        atomic_write_encrypted(
+            &recovery::tmp_name(path),
+            path,
+            &*data_guard,
+            &key,
+            &header,
+            compression,
+        )?;
+        info!("Saved backup of the database to '{path:?}'");
+        Ok(())
+    }
 
-        let mut new_salt = [0u8; SALT_LEN];
-        OsRng.fill_bytes(&mut new_salt);
-        let new_key = derive_key(new_password, &new_salt)?;
+    /// Compression of the plaintext, see [`crate::compression`].
+    pub fn compression(&self) -> Compression {
+        *self.compression.read()
+    }
 
-        atomic_write_encrypted(&self.tmp, &self.path, &*data_guard, &new_key, new_salt)?;
+    /// Changes the compression of the plaintext and saves the database with it.
+    pub fn set_compression(&self, compression: Compression) -> Result<(), Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        // Blocks writers, which use the compression of the database
+        let data_guard = self.data.read();
+        let key = self.key.read();
+        let header = self.header.read();
+        atomic_write_encrypted(
+            &self.tmp,
+            &self.path,
+            &*data_guard,
+            &key,
+            &header,
+            compression,
+        )?;
+        self.dirty.store(false, Ordering::SeqCst);
+        *self.compression.write() = compression;
+        Ok(())
+    }
 
-        {
-            let mut key_lock = self.key.write();
-            *key_lock = new_key;
+    /// Begins a transaction. Changes are only saved on [`EncryptedAtomicDatabaseTransaction::commit`],
+    /// otherwise the previous state is restored.
+    pub fn begin(&self) -> EncryptedAtomicDatabaseTransaction<'_, T>
+    where
+        T: Clone,
+    {
+        let guard = self.write();
+        let backup = guard.data.clone();
+        EncryptedAtomicDatabaseTransaction {
+            guard,
+            backup: Some(backup),
         }
-        {
-            let mut salt_lock = self.salt.write();
-            *salt_lock = new_salt;
+    }
+
+    /// Runs `f` in a transaction, which is committed if `f` returns `Ok`.
+    /// On `Err`, a panic or a failed save, the previous state is restored.
+    pub fn transaction<R, E, F>(&self, f: F) -> Result<R, E>
+    where
+        T: Clone,
+        E: From<Error>,
+        F: FnOnce(&mut T) -> Result<R, E>,
+    {
+        let mut tx = self.begin();
+        let value = f(&mut tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// Changes the password of the key slot the database was opened with.
+    /// Only the slot is re-wrapped, the data isn't re-encrypted.
+    pub fn change_password(&self, new_password: &str) -> Result<(), Error> {
+        self.rekey(Secret::Password(new_password))
+    }
+
+    /// Replaces the secret of the key slot the database was opened with, which may switch
+    /// between password and raw-key mode. Only the slot is re-wrapped.
+    ///
+    /// A password-based slot keeps its key derivation parameters, otherwise the defaults are used.
+    pub fn rekey(&self, secret: Secret) -> Result<(), Error> {
+        let kdf = self.kdf().unwrap_or_default();
+        self.replace_slot(secret, kdf)?;
+        info!("Changed the key of the database to {secret:?}");
+        Ok(())
+    }
+
+    /// Re-wraps the key slot the database was opened with using new key derivation parameters,
+    /// e.g. to increase the cost. The password has to be the current one of the slot.
+    pub fn rehash_kdf(&self, password: &str, kdf: KdfParams) -> Result<(), Error> {
+        kdf.validate()?;
+        let secret = Secret::Password(password);
+        if self.current_slot().unwrap(secret)? != *self.key.read() {
+            return Err(Error::Decryption);
         }
+        self.replace_slot(secret, kdf)?;
+        info!("Rehashed the key of the database with {kdf:?}");
+        Ok(())
+    }
+
+    /// Adds a key slot with the default key derivation parameters, see [`Self::add_key_slot_with`].
+    pub fn add_key_slot(&self, label: &str, secret: Secret) -> Result<u32, Error> {
+        self.add_key_slot_with(label, secret, KdfParams::default())
+    }
+
+    /// Adds a key slot, which can open the database with the given secret, and returns its id.
+    /// The key derivation parameters are only used for passwords.
+    pub fn add_key_slot_with(
+        &self,
+        label: &str,
+        secret: Secret,
+        kdf: KdfParams,
+    ) -> Result<u32, Error> {
+        kdf.validate()?;
+        let id = self.update_header(|header, key| {
+            let id = header.slots().iter().map(|slot| slot.id() + 1).max();
+            let id = id.unwrap_or(0);
+            let slot = KeySlot::wrap(id, label, secret, kdf, key)?;
+            header.slots_mut().push(slot);
+            Ok(id)
+        })?;
+        info!("Added key slot {id} '{label}'");
+        Ok(id)
+    }
+
+    /// Returns the key slots of the database.
+    pub fn key_slots(&self) -> Vec<KeySlot> {
+        self.header.read().slots().to_vec()
+    }
 
+    /// Revokes a key slot, so its secret can't open the database anymore.
+    ///
+    /// The slot the database was opened with can't be revoked. As the data key stays the same,
+    /// copies of the file from before still open with the revoked secret.
+    pub fn revoke_key_slot(&self, id: u32) -> Result<(), Error> {
+        if id == self.slot.load(Ordering::SeqCst) {
+            return Err(Error::KeySlot(format!(
+                "The key slot {id} is in use and can't be revoked"
+            )));
+        }
+        self.update_header(|header, _| {
+            let slots = header.slots_mut();
+            let len = slots.len();
+            slots.retain(|slot| slot.id() != id);
+            if slots.len() == len {
+                return Err(Error::KeySlot(format!("No key slot with id {id}")));
+            }
+            Ok(())
+        })?;
+        info!("Revoked key slot {id}");
         Ok(())
     }
 
-    fn tmp_path(path: &Path) -> io::Result<PathBuf> {
-        let mut tmp_name = OsString::from(".");
-        tmp_name.push(path.file_name().unwrap_or(OsStr::new("db")));
-        tmp_name.push("~");
-        let tmp = path.with_file_name(tmp_name);
-        if tmp.exists() {
-            error!(
-                "Found orphaned database temporary file '{tmp:?}'. The server has recently crashed or is already running. Delete this before continuing!"
-            );
-            return Err(io::Error::new(
-                io::ErrorKind::AlreadyExists,
-                "Orphaned temporary file exists",
-            ));
+    /// Id of the key slot the database was opened with.
+    pub fn key_slot(&self) -> u32 {
+        self.slot.load(Ordering::SeqCst)
+    }
+
+    /// Mode of the key slot the database was opened with.
+    pub fn key_mode(&self) -> KeyMode {
+        *self.current_slot().key_mode()
+    }
+
+    /// Parameters of the key derivation, if the key slot the database was opened with is password-based.
+    pub fn kdf(&self) -> Option<KdfParams> {
+        self.current_slot().kdf().copied()
+    }
+
+    fn current_slot(&self) -> KeySlot {
+        let id = self.slot.load(Ordering::SeqCst);
+        let header = self.header.read();
+        let slot = header
+            .slot(id)
+            .expect("the key slot in use is never revoked");
+        slot.clone()
+    }
+
+    /// Re-wraps the data key in the key slot the database was opened with.
+    fn replace_slot(&self, secret: Secret, kdf: KdfParams) -> Result<(), Error> {
+        let id = self.slot.load(Ordering::SeqCst);
+        self.update_header(|header, key| {
+            let slots = header.slots_mut();
+            let slot = slots.iter_mut().find(|slot| slot.id() == id);
+            let slot = slot.expect("the key slot in use is never revoked");
+            *slot = KeySlot::wrap(id, slot.label(), secret, kdf, key)?;
+            Ok(())
+        })
+    }
+
+    /// Changes the key slots and saves them, reusing the encrypted data of the file.
+    fn update_header<R>(
+        &self,
+        f: impl FnOnce(&mut Header, &Key<Aes256Gcm>) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
         }
-        Ok(tmp)
+        // Blocks writers, so the file contains the current data unless it is dirty
+        let data_guard = self.data.read();
+        let key = *self.key.read();
+        let mut header = self.header.read().clone();
+        let result = f(&mut header, &key)?;
+
+        if self.is_dirty() {
+            let compression = *self.compression.read();
+            atomic_write_encrypted(
+                &self.tmp,
+                &self.path,
+                &*data_guard,
+                &key,
+                &header,
+                compression,
+            )?;
+            self.dirty.store(false, Ordering::SeqCst);
+        } else {
+            // The key slots are not authenticated with the data, so only the header changes
+            let mut encrypted = EncryptedData::from_bytes(&fs::read(&self.path)?)?;
+            encrypted.header = header.clone();
+            {
+                let tmpfile = File::create(&self.tmp)?;
+                write_encrypted(tmpfile, &encrypted)?;
+            }
+            fs::rename(&self.tmp, &self.path)?;
+        }
+
+        *self.header.write() = header;
+        Ok(result)
     }
 }
 
+/// Checks whether the bytes are a complete encrypted file, whose authentication tag matches.
+/// See [`recovery`].
+fn validate(bytes: &[u8], secret: Secret) -> Result<(), Error> {
+    let encrypted = EncryptedData::from_bytes(bytes)?;
+    let (_, key) = encrypted.unlock(secret)?;
+    decrypt_plaintext(&encrypted, &key)?;
+    Ok(())
+}
+
 /// Atomic write routine with encryption
 fn atomic_write_encrypted<T: EncryptedDataStore>(
     tmp: &Path,
     path: &Path,
     data: &T,
     key: &Key<Aes256Gcm>,
-    salt: [u8; SALT_LEN],
-) -> io::Result<()> {
+    header: &Header,
+    compression: Compression,
+) -> Result<(), Error> {
     {
         let tmpfile = File::create(tmp)?;
-        data.save_encrypted(tmpfile, key, salt)?;
+        write_encrypted(tmpfile, &data.encrypt_with(key, header, compression)?)?;
     }
     fs::rename(tmp, path)?;
     Ok(())
@@ -347,16 +1103,102 @@ impl<T: EncryptedDataStore> fmt::Debug for EncryptedAtomicDatabase<T> {
 
 impl<T: EncryptedDataStore> Drop for EncryptedAtomicDatabase<T> {
     fn drop(&mut self) {
+        if self.lock.is_shared() {
+            return;
+        }
         info!("Saving database");
         let data_guard = self.data.read();
         let key = self.key.read();
-        let salt = self.salt.read();
-        if let Err(e) = atomic_write_encrypted(&self.tmp, &self.path, &*data_guard, &key, *salt) {
+        let header = self.header.read();
+        let compression = *self.compression.read();
+        if let Err(e) = atomic_write_encrypted(
+            &self.tmp,
+            &self.path,
+            &*data_guard,
+            &key,
+            &header,
+            compression,
+        ) {
             error!("Failed to save database: {}", e);
         }
     }
 }
 
+/// Read-only handle of an encrypted database file, which has no `write` method.
+///
+/// Like [`ReadOnlyDatabase`](crate::atomic::ReadOnlyDatabase), it never writes any file and doesn't
+/// take the [`lock`](crate::lock), so it can inspect a database, that is opened by another process.
+/// Files in previous formats are not upgraded.
+pub struct EncryptedReadOnlyDatabase<T: EncryptedDataStore> {
+    path: PathBuf,
+    data: RwLock<T>,
+    /// The data key, to decrypt the file again on reloading it.
+    key: Key<Aes256Gcm>,
+    /// Modification time and length of the loaded file.
+    stamp: Mutex<Option<(SystemTime, u64)>>,
+}
+
+impl<T: EncryptedDataStore + DeserializeOwned> EncryptedReadOnlyDatabase<T> {
+    /// Loads the database with the provided password.
+    pub fn load<P: AsRef<Path>>(path: P, password: &str) -> Result<Self, Error> {
+        Self::load_with_secret(path, Secret::Password(password))
+    }
+
+    /// Loads the database with the provided secret, which has to match one of the key slots of the file.
+    ///
+    /// Files of an older schema version are only migrated in memory.
+    pub fn load_with_secret<P: AsRef<Path>>(path: P, secret: Secret) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let stamp = file_stamp(&path);
+        let encrypted = EncryptedData::from_bytes(&fs::read(&path)?)?;
+        let (_, key) = encrypted.unlock(secret)?;
+        let (plaintext, _) = decrypt_plaintext(&encrypted, &key)?;
+        Ok(Self {
+            data: RwLock::new(decode_versioned(&plaintext)?),
+            path,
+            key,
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    /// Locks the database for reading.
+    pub fn read(&self) -> EncryptedAtomicDatabaseRead<'_, T> {
+        EncryptedAtomicDatabaseRead {
+            data: self.data.read(),
+        }
+    }
+
+    /// Reloads the database if its file changed since it was loaded, returning whether it did.
+    ///
+    /// If loading fails, the previous data is kept. This fails with [`Error::Decryption`] if the
+    /// data key was replaced, e.g. by upgrading a file of a previous format.
+    pub fn reload(&self) -> Result<bool, Error> {
+        let mut stamp = self.stamp.lock();
+        let current = file_stamp(&self.path);
+        if current == *stamp {
+            return Ok(false);
+        }
+        let encrypted = EncryptedData::from_bytes(&fs::read(&self.path)?)?;
+        let (plaintext, _) = decrypt_plaintext(&encrypted, &self.key)?;
+        *self.data.write() = decode_versioned(&plaintext)?;
+        *stamp = current;
+        Ok(true)
+    }
+
+    /// Path of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<T: EncryptedDataStore> fmt::Debug for EncryptedReadOnlyDatabase<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedReadOnlyDatabase")
+            .field("file", &self.path)
+            .finish()
+    }
+}
+
 pub struct EncryptedAtomicDatabaseRead<'a, T: EncryptedDataStore> {
     data: RwLockReadGuard<'a, T>,
 }
@@ -373,7 +1215,42 @@ pub struct EncryptedAtomicDatabaseWrite<'a, T: EncryptedDataStore> {
     path: &'a Path,
     data: RwLockWriteGuard<'a, T>,
     key: Key<Aes256Gcm>,
-    salt: [u8; SALT_LEN],
+    header: Header,
+    compression: Compression,
+    save: bool,
+    read_only: bool,
+    dirty: &'a AtomicBool,
+    backup: Option<&'a Backups>,
+}
+
+impl<'a, T: EncryptedDataStore> EncryptedAtomicDatabaseWrite<'a, T> {
+    /// Saves the changes atomically, returning the error instead of only logging it.
+    ///
+    /// If this fails, the database is marked as dirty, see [`EncryptedAtomicDatabase::is_dirty`].
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.save = false;
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let result = atomic_write_encrypted(
+            self.tmp,
+            self.path,
+            &*self.data,
+            &self.key,
+            &self.header,
+            self.compression,
+        );
+        self.dirty.store(result.is_err(), Ordering::SeqCst);
+        if let (Ok(_), Some(backup)) = (&result, self.backup) {
+            let data = &*self.data;
+            backup.written(self.path, data, &self.key, &self.header, self.compression);
+        }
+        result
+    }
 }
 
 impl<'a, T: EncryptedDataStore> Deref for EncryptedAtomicDatabaseWrite<'a, T> {
@@ -391,11 +1268,225 @@ impl<'a, T: EncryptedDataStore> DerefMut for EncryptedAtomicDatabaseWrite<'a, T>
 
 impl<'a, T: EncryptedDataStore> Drop for EncryptedAtomicDatabaseWrite<'a, T> {
     fn drop(&mut self) {
+        if !self.save || self.read_only {
+            return;
+        }
         info!("Saving database");
-        if let Err(e) =
-            atomic_write_encrypted(self.tmp, self.path, &*self.data, &self.key, self.salt)
-        {
+        if let Err(e) = self.persist() {
             error!("Failed to save database: {}", e);
         }
     }
 }
+
+/// Write guard of a transaction, see [`EncryptedAtomicDatabase::begin`].
+///
+/// Dropping it without calling [`commit`](Self::commit), also due to a panic, rolls the changes back.
+pub struct EncryptedAtomicDatabaseTransaction<'a, T: EncryptedDataStore> {
+    guard: EncryptedAtomicDatabaseWrite<'a, T>,
+    /// State before the transaction, `None` once committed.
+    backup: Option<T>,
+}
+
+impl<'a, T: EncryptedDataStore> EncryptedAtomicDatabaseTransaction<'a, T> {
+    /// Commits the changes and saves them atomically.
+    ///
+    /// If saving fails, the changes are rolled back and the error is returned.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let backup = self.backup.take();
+        self.guard.save = false;
+        let dirty = self.guard.dirty.load(Ordering::SeqCst);
+        let result = self.guard.persist();
+        if let (Err(_), Some(backup)) = (&result, backup) {
+            // The data matches the file again, unless a previous save failed
+            *self.guard.data = backup;
+            self.guard.dirty.store(dirty, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Discards the changes, restoring the state before the transaction.
+    pub fn rollback(self) {}
+}
+
+impl<'a, T: EncryptedDataStore> Deref for EncryptedAtomicDatabaseTransaction<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T: EncryptedDataStore> DerefMut for EncryptedAtomicDatabaseTransaction<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: EncryptedDataStore> Drop for EncryptedAtomicDatabaseTransaction<'a, T> {
+    fn drop(&mut self) {
+        if let Some(backup) = self.backup.take() {
+            info!("Rolling back transaction");
+            *self.guard.data = backup;
+            // nothing changed, so there is nothing to save
+            self.guard.save = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, generate_key, EncryptedDataStore, NONCE_LEN, SALT_LEN};
+    use crate::header::{Cipher, HeaderV1, HeaderV2, KdfParams, KeyMode, FORMAT_VERSION, MAGIC};
+    use aes_gcm::{
+        aead::{Aead, Payload},
+        Aes256Gcm, KeyInit, Nonce,
+    };
+    use serde::{Deserialize, Serialize};
+    use std::fs;
+
+    #[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+    struct Data {
+        items: Vec<String>,
+    }
+
+    impl EncryptedDataStore for Data {}
+
+    #[derive(Serialize)]
+    struct LegacyEncryptedData {
+        salt: [u8; SALT_LEN],
+        nonce: [u8; NONCE_LEN],
+        ciphertext: Vec<u8>,
+    }
+
+    #[derive(Serialize)]
+    struct EncryptedDataV1 {
+        header: HeaderV1,
+        nonce: [u8; NONCE_LEN],
+        ciphertext: Vec<u8>,
+    }
+
+    #[derive(Serialize)]
+    struct EncryptedDataV2 {
+        header: HeaderV2,
+        nonce: [u8; NONCE_LEN],
+        ciphertext: Vec<u8>,
+    }
+
+    fn data() -> Data {
+        Data {
+            items: vec!["Item 1".into()],
+        }
+    }
+
+    /// Opens the file twice, checking it was upgraded to the current format.
+    fn check_upgrade(
+        path: &std::path::Path,
+        open: impl Fn() -> crate::encrypted::EncryptedAtomicDatabase<Data>,
+    ) {
+        {
+            let db = open();
+            assert_eq!(*db.read(), data());
+            assert_eq!(db.key_slots().len(), 1);
+        }
+        let bytes = fs::read(path).unwrap();
+        assert!(bytes.starts_with(&MAGIC));
+        assert_eq!(
+            bytes[MAGIC.len()..MAGIC.len() + 2],
+            FORMAT_VERSION.to_le_bytes()
+        );
+        let db = open();
+        assert_eq!(*db.read(), data());
+        drop(db);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(crate::lock::lock_path(path)).unwrap();
+    }
+
+    #[test]
+    fn legacy_upgrade() {
+        let path = std::env::temp_dir().join(format!("legacy_{}.db", std::process::id()));
+
+        // Written without a header and without associated data
+        let salt = [3; SALT_LEN];
+        let nonce = [5; NONCE_LEN];
+        let key = KdfParams::default().derive_key("password", &salt).unwrap();
+        let ciphertext = Aes256Gcm::new(&key)
+            .encrypt(Nonce::from_slice(&nonce), &encode(&data()).unwrap()[..])
+            .unwrap();
+        let legacy = LegacyEncryptedData {
+            salt,
+            nonce,
+            ciphertext,
+        };
+        fs::write(&path, encode(&legacy).unwrap()).unwrap();
+
+        check_upgrade(&path, || Data::open(&path, "password").unwrap());
+    }
+
+    #[test]
+    fn v1_upgrade() {
+        let path = std::env::temp_dir().join(format!("v1_{}.db", std::process::id()));
+
+        // A single password-derived key, the whole header is associated data
+        let kdf = KdfParams::new().memory_cost(8).time_cost(1);
+        let salt = [3; SALT_LEN];
+        let key = kdf.derive_key("password", &salt).unwrap();
+        let header = HeaderV1 {
+            magic: MAGIC,
+            version: 1,
+            cipher: Cipher::Aes256Gcm,
+            kdf,
+            salt,
+        };
+        let nonce = [5; NONCE_LEN];
+        let payload = Payload {
+            msg: &encode(&data()).unwrap(),
+            aad: &encode(&header).unwrap(),
+        };
+        let ciphertext = Aes256Gcm::new(&key)
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .unwrap();
+        let v1 = EncryptedDataV1 {
+            header,
+            nonce,
+            ciphertext,
+        };
+        fs::write(&path, encode(&v1).unwrap()).unwrap();
+
+        check_upgrade(&path, || {
+            let db = Data::open(&path, "password").unwrap();
+            // The parameters of the previous header are kept
+            assert_eq!(db.kdf(), Some(kdf));
+            db
+        });
+    }
+
+    #[test]
+    fn v2_upgrade() {
+        let path = std::env::temp_dir().join(format!("v2_{}.db", std::process::id()));
+
+        // A single raw key, the whole header is associated data
+        let key = generate_key();
+        let header = HeaderV2 {
+            magic: MAGIC,
+            version: 2,
+            cipher: Cipher::Aes256Gcm,
+            key_mode: KeyMode::Key,
+            salt: [3; SALT_LEN],
+        };
+        let nonce = [5; NONCE_LEN];
+        let payload = Payload {
+            msg: &encode(&data()).unwrap(),
+            aad: &encode(&header).unwrap(),
+        };
+        let ciphertext = Aes256Gcm::new(&key)
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .unwrap();
+        let v2 = EncryptedDataV2 {
+            header,
+            nonce,
+            ciphertext,
+        };
+        fs::write(&path, encode(&v2).unwrap()).unwrap();
+
+        check_upgrade(&path, || Data::open_with_key(&path, &key).unwrap());
+    }
+}
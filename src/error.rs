@@ -0,0 +1,136 @@
+use std::{error, fmt, io, path::PathBuf};
+
+/// Error type of opening, loading and saving a database, shared by the
+/// atomic and the encrypted database.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the database files failed.
+    Io(io::Error),
+    /// The content of the database file could not be deserialized.
+    Deserialize {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// The data could not be encoded or decoded, e.g. the binary content of an encrypted file.
+    Encoding(String),
+    /// An orphaned temporary file exists, because the database has recently crashed.
+    /// See [`recovery`](crate::recovery) for recovering from it automatically.
+    OrphanedTmpFile(PathBuf),
+    /// Decryption failed because of an incorrect password or corrupted data.
+    Decryption,
+    /// Encryption of the data failed.
+    Encryption,
+    /// Deriving the key from the password failed.
+    KeyDerivation,
+    /// A previous save failed, so the in-memory data diverged from the file.
+    Dirty,
+    /// The file has a newer schema version than the supported one.
+    Version { found: u32, supported: u32 },
+    /// Migrating the data to a newer schema version failed.
+    Migration(String),
+    /// A key slot of an encrypted file could not be changed, e.g. because it doesn't exist.
+    KeySlot(String),
+    /// The database is opened by another handle, which holds the lock, see [`lock`](crate::lock).
+    /// The process id of the owner is known if it opened the database for writing.
+    Locked { pid: Option<u32> },
+    /// The database was opened read-only, so changes can't be saved.
+    ReadOnly,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::Deserialize {
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "Failed to deserialize database at line {line} column {column}: {message}"
+            ),
+            Error::Encoding(message) => write!(f, "Failed to encode or decode data: {message}"),
+            Error::OrphanedTmpFile(path) => write!(
+                f,
+                "Orphaned temporary file '{}' exists, delete it before continuing",
+                path.display()
+            ),
+            Error::Decryption => {
+                f.write_str("Decryption failed: Incorrect password or corrupted data")
+            }
+            Error::Encryption => f.write_str("Encryption failed"),
+            Error::KeyDerivation => f.write_str("Key derivation failed"),
+            Error::Dirty => f.write_str(
+                "A previous save failed, the database has to be saved successfully before continuing",
+            ),
+            Error::Version { found, supported } => write!(
+                f,
+                "The database has the schema version {found}, but only up to {supported} is supported"
+            ),
+            Error::Migration(message) => write!(f, "Failed to migrate database: {message}"),
+            Error::KeySlot(message) => write!(f, "Invalid key slot operation: {message}"),
+            Error::Locked { pid: Some(pid) } => write!(f, "The database is locked by PID {pid}"),
+            Error::Locked { pid: None } => {
+                f.write_str("The database is locked by another process")
+            }
+            Error::ReadOnly => f.write_str("The database is opened read-only"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            return Error::Io(e.into());
+        }
+        // Strip the position suffix, which is already part of the variant
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(pos) if e.line() > 0 => message[..pos].to_string(),
+            _ => message,
+        };
+        Error::Deserialize {
+            line: e.line(),
+            column: e.column(),
+            message,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match &e {
+            Error::Io(_) => io::ErrorKind::Other,
+            Error::Deserialize { .. }
+            | Error::Encoding(_)
+            | Error::Decryption
+            | Error::Version { .. }
+            | Error::Migration(_) => io::ErrorKind::InvalidData,
+            Error::OrphanedTmpFile(_) => io::ErrorKind::AlreadyExists,
+            Error::KeySlot(_) => io::ErrorKind::InvalidInput,
+            Error::Locked { .. } => io::ErrorKind::WouldBlock,
+            Error::ReadOnly => io::ErrorKind::PermissionDenied,
+            Error::Encryption | Error::KeyDerivation | Error::Dirty => io::ErrorKind::Other,
+        };
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(kind, e),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! On-disk formats of the [`AtomicDatabase`](crate::atomic::AtomicDatabase).
+//!
+//! The format is selected with [`OpenOptions::format`](crate::atomic::OpenOptions::format)
+//! and detected when loading a file, so files are converted on opening them with another format.
+//! [`AtomicDatabase::convert`](crate::atomic::AtomicDatabase::convert) converts a file without
+//! opening the database.
+//!
+//! Binary files start with a short header of the format and the schema version, followed by
+//! the encoded data. Tables are stored as sequences of rows, like in the encrypted database.
+//! Schema migrations work on the JSON representation, so older binary files have to be
+//! converted to JSON (by the previous version of the application) before migrating them.
+
+use serde::de::DeserializeOwned;
+use std::io::Write;
+
+use crate::{atomic::DataStore, error::Error, migration};
+
+/// Prefix of binary database files, followed by the format id and the schema version.
+const MAGIC: [u8; 8] = *b"LMAGICBN";
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+
+/// Format of a database file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Pretty printed JSON, written by [`DataStore::save`]. The default for new files.
+    Json,
+    /// JSON without any whitespace.
+    JsonCompact,
+    /// Compact binary encoding of [`bincode`](https://docs.rs/bincode).
+    #[cfg(feature = "bincode")]
+    Bincode,
+    /// Concise Binary Object Representation (RFC 8949).
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// MessagePack, with structs encoded as maps.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    /// Detects the format of the contents of a database file.
+    ///
+    /// JSON files are detected as [`Format::Json`] if they contain line breaks.
+    pub fn detect(bytes: &[u8]) -> Result<Self, Error> {
+        let Some(rest) = bytes.strip_prefix(&MAGIC[..]) else {
+            return Ok(match bytes.contains(&b'\n') {
+                true => Format::Json,
+                false => Format::JsonCompact,
+            });
+        };
+        let feature = match rest.first() {
+            Some(1) => "bincode",
+            Some(2) => "cbor",
+            Some(3) => "msgpack",
+            Some(id) => return Err(Error::Encoding(format!("unknown binary format {id}"))),
+            None => return Err(Error::Encoding("truncated binary header".into())),
+        };
+        match feature {
+            #[cfg(feature = "bincode")]
+            "bincode" => Ok(Format::Bincode),
+            #[cfg(feature = "cbor")]
+            "cbor" => Ok(Format::Cbor),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Ok(Format::MessagePack),
+            feature => Err(Error::Encoding(format!(
+                "the file is stored in a binary format, which requires the `{feature}` feature"
+            ))),
+        }
+    }
+
+    /// Whether this is a binary format.
+    pub fn is_binary(self) -> bool {
+        self.id().is_some()
+    }
+
+    /// Id of a binary format in the header.
+    fn id(self) -> Option<u8> {
+        match self {
+            Format::Json | Format::JsonCompact => None,
+            #[cfg(feature = "bincode")]
+            Format::Bincode => Some(1),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => Some(2),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => Some(3),
+        }
+    }
+
+    /// Reads the schema version of a file in this format.
+    pub(crate) fn version(self, bytes: &[u8]) -> Result<u32, Error> {
+        match self.id() {
+            None => migration::json_version(bytes),
+            Some(_) => Ok(split_header(bytes)?.0),
+        }
+    }
+
+    /// Decodes a file in this format, which has the current schema version.
+    pub(crate) fn read<T: DataStore + DeserializeOwned>(self, bytes: &[u8]) -> Result<T, Error> {
+        match self {
+            Format::Json | Format::JsonCompact => T::load(bytes),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => {
+                let config = bincode::config::standard();
+                bincode::serde::decode_from_slice(split_header(bytes)?.1, config)
+                    .map(|(data, _)| data)
+                    .map_err(encoding)
+            }
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::from_reader(split_header(bytes)?.1).map_err(encoding),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::from_slice(split_header(bytes)?.1).map_err(encoding),
+        }
+    }
+
+    /// Encodes the data in this format, along its [`DataStore::VERSION`].
+    pub(crate) fn write<T: DataStore>(self, data: &T, mut file: impl Write) -> Result<(), Error> {
+        if let Some(id) = self.id() {
+            file.write_all(&MAGIC)?;
+            file.write_all(&[id])?;
+            file.write_all(&T::VERSION.to_le_bytes())?;
+        }
+        match self {
+            Format::Json => data.save(file),
+            Format::JsonCompact => {
+                let result = if T::VERSION > 0 {
+                    let versioned = migration::Versioned {
+                        version: T::VERSION,
+                        data,
+                    };
+                    serde_json::to_writer(file, &versioned)
+                } else {
+                    serde_json::to_writer(file, data)
+                };
+                result.map_err(|e| match e.is_io() {
+                    true => Error::Io(e.into()),
+                    false => Error::Encoding(e.to_string()),
+                })
+            }
+            #[cfg(feature = "bincode")]
+            Format::Bincode => {
+                let config = bincode::config::standard();
+                bincode::serde::encode_into_std_write(data, &mut file, config)
+                    .map(|_| ())
+                    .map_err(|e| match e {
+                        bincode::error::EncodeError::Io { inner, .. } => Error::Io(inner),
+                        e => encoding(e),
+                    })
+            }
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::into_writer(data, file).map_err(|e| match e {
+                ciborium::ser::Error::Io(e) => Error::Io(e),
+                e => encoding(e),
+            }),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => {
+                let bytes = rmp_serde::to_vec_named(data).map_err(encoding)?;
+                Ok(file.write_all(&bytes)?)
+            }
+        }
+    }
+}
+
+/// Splits the binary header into the schema version and the encoded data.
+fn split_header(bytes: &[u8]) -> Result<(u32, &[u8]), Error> {
+    if bytes.len() < HEADER_LEN {
+        return Err(Error::Encoding("truncated binary header".into()));
+    }
+    let (header, data) = bytes.split_at(HEADER_LEN);
+    let version = u32::from_le_bytes(header[MAGIC.len() + 1..].try_into().unwrap());
+    Ok((version, data))
+}
+
+#[cfg(any(feature = "bincode", feature = "cbor", feature = "msgpack"))]
+fn encoding(e: impl std::fmt::Display) -> Error {
+    Error::Encoding(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Format;
+    use crate::{
+        atomic::DataStore,
+        table::{PrimaryKey, Table},
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct Data {
+        users: Table<User>,
+    }
+
+    impl DataStore for Data {
+        const VERSION: u32 = 2;
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+    struct User {
+        id: usize,
+        name: String,
+    }
+
+    impl PrimaryKey for User {
+        type PrimaryKeyType = usize;
+
+        fn primary_key(&self) -> Self::PrimaryKeyType {
+            self.id
+        }
+    }
+
+    fn formats() -> Vec<Format> {
+        vec![
+            Format::Json,
+            Format::JsonCompact,
+            #[cfg(feature = "bincode")]
+            Format::Bincode,
+            #[cfg(feature = "cbor")]
+            Format::Cbor,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack,
+        ]
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = Data::default();
+        for id in 0..3 {
+            data.users.add(User {
+                id,
+                name: format!("User {id}"),
+            });
+        }
+        for format in formats() {
+            let mut bytes = Vec::new();
+            format.write(&data, &mut bytes).unwrap();
+            assert_eq!(Format::detect(&bytes).unwrap(), format);
+            assert_eq!(format.version(&bytes).unwrap(), 2);
+            let back: Data = format.read(&bytes).unwrap();
+            assert_eq!(back.users.len(), 3, "{format:?}");
+            assert_eq!(back.users.get(&2), data.users.get(&2));
+        }
+    }
+
+    #[test]
+    fn unknown_binary_format() {
+        assert!(Format::detect(b"LMAGICBN\x09").is_err());
+        assert!(Format::detect(b"LMAGICBN").is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//! Header of the encrypted file format.
+//!
+//! Every encrypted file starts with a header describing how it was encrypted: magic bytes,
+//! the format version, the cipher and the key slots. The magic bytes, version and cipher are
+//! authenticated as associated data of the AEAD cipher, so they cannot be altered unnoticed.
+//!
+//! The data is encrypted with a random data key, which is wrapped by each [`KeySlot`] with a
+//! key obtained from a password or a raw key ([`KeyMode`]). Any slot can open the database and
+//! slots can be added or revoked without re-encrypting the data.
+//! Note that revoking a slot doesn't change the data key, a revoked secret still opens old
+//! copies of the file.
+//!
+//! Every change of the header layout gets a new format version. Files of version 1 (a single
+//! password-derived key), version 2 (a single password-derived or raw key) and files written
+//! before the header existed, which start directly with the salt, are still readable and upgraded
+//! when loaded.
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use zeroize::Zeroize;
+
+use crate::{
+    encrypted::{encode, Secret, NONCE_LEN, SALT_LEN},
+    error::Error,
+};
+
+/// Magic bytes at the start of every encrypted file.
+pub(crate) const MAGIC: [u8; 8] = *b"LMAGICDB";
+
+/// Current version of the encrypted file format.
+pub(crate) const FORMAT_VERSION: u16 = 3;
+
+/// Cipher used for encrypting the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    /// AES-256 in Galois/Counter Mode with 96-bit nonces.
+    Aes256Gcm,
+}
+
+/// Variant of the Argon2 key derivation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KdfAlgorithm {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+/// Parameters of the Argon2 key derivation, stored in the header of the file.
+///
+/// Higher costs make guessing passwords more expensive, but also opening the database.
+///
+/// ```
+/// use light_magic::header::{KdfAlgorithm, KdfParams};
+///
+/// let params = KdfParams::new()
+///     .algorithm(KdfAlgorithm::Argon2id)
+///     .memory_cost(64 * 1024)
+///     .time_cost(3)
+///     .parallelism(4);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    algorithm: KdfAlgorithm,
+    /// Memory cost in KiB.
+    memory_cost: u32,
+    /// Number of iterations.
+    time_cost: u32,
+    /// Degree of parallelism.
+    parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The defaults of the `argon2` crate, which were used for all files without a header.
+    fn default() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory_cost: Params::DEFAULT_M_COST,
+            time_cost: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Creates the default parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the variant of Argon2.
+    pub fn algorithm(mut self, algorithm: KdfAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Sets the memory cost in KiB, at least `8 * parallelism`.
+    pub fn memory_cost(mut self, memory_cost: u32) -> Self {
+        self.memory_cost = memory_cost;
+        self
+    }
+
+    /// Sets the number of iterations, at least `1`.
+    pub fn time_cost(mut self, time_cost: u32) -> Self {
+        self.time_cost = time_cost;
+        self
+    }
+
+    /// Sets the degree of parallelism, at least `1`.
+    pub fn parallelism(mut self, parallelism: u32) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Fails with [`Error::KeyDerivation`] if the parameters are out of range.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.params().map(|_| ())
+    }
+
+    fn params(&self) -> Result<Params, Error> {
+        Params::new(
+            self.memory_cost,
+            self.time_cost,
+            self.parallelism,
+            Some(Params::DEFAULT_OUTPUT_LEN),
+        )
+        .map_err(|_| Error::KeyDerivation)
+    }
+
+    /// Derives a 32-byte key from the password and salt.
+    pub(crate) fn derive_key(&self, password: &str, salt: &[u8]) -> Result<Key<Aes256Gcm>, Error> {
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2d => Algorithm::Argon2d,
+            KdfAlgorithm::Argon2i => Algorithm::Argon2i,
+            KdfAlgorithm::Argon2id => Algorithm::Argon2id,
+        };
+        let mut key = [0u8; 32];
+        Argon2::new(algorithm, Version::V0x13, self.params()?)
+            .hash_password_into(password.as_bytes(), salt, &mut key)
+            .map_err(|_| Error::KeyDerivation)?;
+
+        let out = *Key::<Aes256Gcm>::from_slice(&key);
+        key.zeroize(); // wipe stack buffer
+        Ok(out)
+    }
+}
+
+/// How the key of an encrypted file is obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyMode {
+    /// Derived from a password with the given parameters.
+    Password(KdfParams),
+    /// A raw 256-bit key, e.g. from a keyfile or a secrets manager.
+    Key,
+}
+
+/// A slot wrapping the data key with a key obtained from a [`Secret`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeySlot {
+    id: u32,
+    label: String,
+    key_mode: KeyMode,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+    wrapped_key: Vec<u8>,
+}
+
+impl KeySlot {
+    /// Wraps the data key with the key obtained from the secret.
+    pub(crate) fn wrap(
+        id: u32,
+        label: &str,
+        secret: Secret,
+        kdf: KdfParams,
+        data_key: &Key<Aes256Gcm>,
+    ) -> Result<Self, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let key_mode = match secret {
+            Secret::Password(_) => KeyMode::Password(kdf),
+            Secret::Key(_) => KeyMode::Key,
+        };
+
+        let mut slot = Self {
+            id,
+            label: label.into(),
+            key_mode,
+            salt,
+            nonce,
+            wrapped_key: Vec::new(),
+        };
+        let payload = Payload {
+            msg: data_key.as_slice(),
+            aad: &slot.aad()?,
+        };
+        slot.wrapped_key = Aes256Gcm::new(&slot.key_mode.key(secret, &salt)?)
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::Encryption)?;
+        Ok(slot)
+    }
+
+    /// Unwraps the data key, failing with [`Error::Decryption`] for a wrong secret.
+    pub(crate) fn unwrap(&self, secret: Secret) -> Result<Key<Aes256Gcm>, Error> {
+        let payload = Payload {
+            msg: &self.wrapped_key,
+            aad: &self.aad()?,
+        };
+        let mut data_key = Aes256Gcm::new(&self.key_mode.key(secret, &self.salt)?)
+            .decrypt(Nonce::from_slice(&self.nonce), payload)
+            .map_err(|_| Error::Decryption)?;
+        if data_key.len() != 32 {
+            return Err(Error::Decryption);
+        }
+        let out = *Key::<Aes256Gcm>::from_slice(&data_key);
+        data_key.zeroize(); // wipe heap buffer
+        Ok(out)
+    }
+
+    /// Authenticates the metadata of the slot.
+    fn aad(&self) -> Result<Vec<u8>, Error> {
+        encode(&(self.id, &self.label, &self.key_mode, &self.salt))
+    }
+
+    /// Identifier of the slot, unique within the file.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Label of the slot, e.g. `"operator"` or `"recovery"`.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// How the key of the slot is obtained.
+    pub fn key_mode(&self) -> &KeyMode {
+        &self.key_mode
+    }
+
+    /// Parameters of the key derivation, if the slot is password-based.
+    pub fn kdf(&self) -> Option<&KdfParams> {
+        match &self.key_mode {
+            KeyMode::Password(kdf) => Some(kdf),
+            KeyMode::Key => None,
+        }
+    }
+}
+
+impl KeyMode {
+    /// Returns the key for the secret, deriving it if it is password-based.
+    ///
+    /// Fails with [`Error::Decryption`] if the secret doesn't match the mode.
+    pub(crate) fn key(
+        &self,
+        secret: Secret,
+        salt: &[u8; SALT_LEN],
+    ) -> Result<Key<Aes256Gcm>, Error> {
+        match (self, secret) {
+            (KeyMode::Password(kdf), Secret::Password(password)) => kdf.derive_key(password, salt),
+            (KeyMode::Key, Secret::Key(key)) => Ok(*key),
+            _ => Err(Error::Decryption),
+        }
+    }
+}
+
+/// Header of an encrypted file, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    magic: [u8; 8],
+    version: u16,
+    cipher: Cipher,
+    slots: Vec<KeySlot>,
+}
+
+impl Header {
+    /// Creates a header of the current format version.
+    pub(crate) fn new(slots: Vec<KeySlot>) -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            cipher: Cipher::Aes256Gcm,
+            slots,
+        }
+    }
+
+    /// Unwraps the data key with the first matching slot, returning its id.
+    pub(crate) fn unlock(&self, secret: Secret) -> Result<(u32, Key<Aes256Gcm>), Error> {
+        for slot in &self.slots {
+            match slot.unwrap(secret) {
+                Ok(key) => return Ok((slot.id, key)),
+                Err(Error::Decryption) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        error!("No key slot of the encrypted file matches the {secret:?}");
+        Err(Error::Decryption)
+    }
+
+    /// Fails if the header is not of a supported format version.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.magic != MAGIC {
+            return Err(Error::Encoding(
+                "Missing magic bytes of an encrypted file".into(),
+            ));
+        }
+        if self.version > FORMAT_VERSION {
+            return Err(Error::Encoding(format!(
+                "Unsupported encrypted file format version {}",
+                self.version
+            )));
+        }
+        Ok(())
+    }
+
+    /// Encodes the header as associated data of the cipher, without the key slots.
+    pub(crate) fn aad(&self) -> Result<Vec<u8>, Error> {
+        encode(&(self.magic, self.version, self.cipher))
+    }
+
+    /// Version of the file format.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Cipher used for encrypting the data.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Slots wrapping the data key.
+    pub fn slots(&self) -> &[KeySlot] {
+        &self.slots
+    }
+
+    /// Slot with the given id.
+    pub fn slot(&self, id: u32) -> Option<&KeySlot> {
+        self.slots.iter().find(|slot| slot.id == id)
+    }
+
+    pub(crate) fn slots_mut(&mut self) -> &mut Vec<KeySlot> {
+        &mut self.slots
+    }
+}
+
+/// Header of format version 1, with a single key derived directly from a password.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HeaderV1 {
+    pub(crate) magic: [u8; 8],
+    pub(crate) version: u16,
+    pub(crate) cipher: Cipher,
+    pub(crate) kdf: KdfParams,
+    pub(crate) salt: [u8; SALT_LEN],
+}
+
+/// Header of format version 2, with a single key obtained directly from the secret.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HeaderV2 {
+    pub(crate) magic: [u8; 8],
+    pub(crate) version: u16,
+    pub(crate) cipher: Cipher,
+    pub(crate) key_mode: KeyMode,
+    pub(crate) salt: [u8; SALT_LEN],
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Header, KdfParams, KeySlot, MAGIC};
+    use crate::{
+        encrypted::{decode, encode, generate_key, Secret},
+        Error,
+    };
+
+    #[test]
+    fn header_roundtrip() {
+        let slot = KeySlot::wrap(
+            0,
+            "default",
+            Secret::Key(&generate_key()),
+            KdfParams::default(),
+            &generate_key(),
+        )
+        .unwrap();
+        let header = Header::new(vec![slot]);
+        let bytes = encode(&header).unwrap();
+        assert!(bytes.starts_with(&MAGIC));
+        let back: Header = decode(&bytes).unwrap();
+        assert_eq!(back, header);
+        back.check().unwrap();
+    }
+
+    #[test]
+    fn key_slots() {
+        let data_key = generate_key();
+        let key = generate_key();
+        let cheap = KdfParams::new().memory_cost(8).time_cost(1);
+        let header = Header::new(vec![
+            KeySlot::wrap(
+                0,
+                "operator",
+                Secret::Password("password"),
+                cheap,
+                &data_key,
+            )
+            .unwrap(),
+            KeySlot::wrap(1, "recovery", Secret::Key(&key), cheap, &data_key).unwrap(),
+        ]);
+        assert_eq!(header.slot(0).unwrap().kdf(), Some(&cheap));
+        assert_eq!(header.slot(1).unwrap().kdf(), None);
+
+        assert_eq!(
+            header.unlock(Secret::Password("password")).unwrap(),
+            (0, data_key)
+        );
+        assert_eq!(header.unlock(Secret::Key(&key)).unwrap(), (1, data_key));
+        assert!(matches!(
+            header.unlock(Secret::Password("wrong")),
+            Err(Error::Decryption)
+        ));
+        assert!(matches!(
+            header.unlock(Secret::Key(&generate_key())),
+            Err(Error::Decryption)
+        ));
+
+        // The metadata of a slot is authenticated
+        let mut tampered = header.slot(1).unwrap().clone();
+        tampered.label = "operator".into();
+        assert!(tampered.unwrap(Secret::Key(&key)).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Secondary indexes of a [`Table`](crate::table::Table).
+//!
+//! Indexes are declared by the row type in [`PrimaryKey::indexes`] and maintained by the table.
+//! They are never serialized, but rebuilt when the table is deserialized.
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::RangeBounds,
+    sync::Arc,
+};
+
+use crate::table::PrimaryKey;
+
+/// Secondary indexes of a table, mapping extracted keys to primary keys.
+///
+/// ```
+/// use light_magic::{
+///     index::Indexes,
+///     serde::{Deserialize, Serialize},
+///     table::{PrimaryKey, Table},
+/// };
+///
+/// #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+/// struct User {
+///     id: usize,
+///     name: String,
+///     age: usize,
+/// }
+///
+/// impl PrimaryKey for User {
+///     type PrimaryKeyType = usize;
+///
+///     fn primary_key(&self) -> Self::PrimaryKeyType {
+///         self.id
+///     }
+///
+///     fn indexes(indexes: &mut Indexes<Self>) {
+///         indexes.add("name", |user: &User| user.name.clone());
+///         indexes.add("age", |user: &User| user.age);
+///     }
+/// }
+///
+/// let mut users = Table::default();
+/// users.add(User { id: 0, name: "Nils".into(), age: 21 });
+/// users.add(User { id: 1, name: "Alice".into(), age: 42 });
+///
+/// assert_eq!(users.get_by_index("name", &String::from("Nils")).unwrap()[0].id, 0);
+/// assert_eq!(users.range_by_index("age", 30usize..).unwrap()[0].id, 1);
+/// ```
+pub struct Indexes<V: PrimaryKey> {
+    indexes: BTreeMap<&'static str, Box<dyn DynIndex<V> + Send + Sync>>,
+    /// Rows that were borrowed mutably and have to be indexed again.
+    pending: BTreeSet<V::PrimaryKeyType>,
+    /// All rows have to be indexed again.
+    stale: bool,
+}
+
+impl<V: PrimaryKey> Indexes<V>
+where
+    V::PrimaryKeyType: Ord + Clone,
+{
+    /// Registers an index with the key extracted by `extractor`.
+    pub fn add<K, F>(&mut self, name: &'static str, extractor: F)
+    where
+        V: 'static,
+        V::PrimaryKeyType: Send + Sync + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        F: Fn(&V) -> K + Send + Sync + 'static,
+    {
+        self.insert_index(name, extractor, false);
+    }
+
+    /// Registers a unique index, that rejects entries with an already existing key.
+    ///
+    /// Violations are rejected by [`Table::try_add`](crate::table::Table::try_add),
+    /// [`Table::try_edit`](crate::table::Table::try_edit) and
+    /// [`Table::try_modify`](crate::table::Table::try_modify). Violations by mutable borrows or in
+    /// loaded files are only logged, see [`Table::validate`](crate::table::Table::validate).
+    pub fn unique<K, F>(&mut self, name: &'static str, extractor: F)
+    where
+        V: 'static,
+        V::PrimaryKeyType: Send + Sync + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        F: Fn(&V) -> K + Send + Sync + 'static,
+    {
+        self.insert_index(name, extractor, true);
+    }
+
+    fn insert_index<K, F>(&mut self, name: &'static str, extractor: F, unique: bool)
+    where
+        V: 'static,
+        V::PrimaryKeyType: Send + Sync + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        F: Fn(&V) -> K + Send + Sync + 'static,
+    {
+        self.indexes.insert(
+            name,
+            Box::new(Index {
+                extractor: Arc::new(extractor),
+                entries: BTreeMap::new(),
+                unique,
+            }),
+        );
+    }
+
+    /// Creates the indexes declared by the row type.
+    pub(crate) fn declared() -> Self {
+        let mut indexes = Self {
+            indexes: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            stale: false,
+        };
+        V::indexes(&mut indexes);
+        indexes
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, key: &V::PrimaryKeyType, value: &V) {
+        for index in self.indexes.values_mut() {
+            index.insert(key, value);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &V::PrimaryKeyType, value: &V) {
+        for index in self.indexes.values_mut() {
+            index.remove(key, value);
+        }
+    }
+
+    /// Finds an entry, other than `ignore`, with the same key as `value` in a unique index.
+    ///
+    /// Expects the indexes to be up to date.
+    pub(crate) fn conflict(
+        &self,
+        value: &V,
+        ignore: Option<&V::PrimaryKeyType>,
+    ) -> Option<(&'static str, V::PrimaryKeyType)> {
+        self.indexes.iter().find_map(|(name, index)| {
+            index
+                .conflict(value, ignore)
+                .map(|existing| (*name, existing))
+        })
+    }
+
+    /// Finds the primary keys of all entries with duplicate keys in a unique index.
+    pub(crate) fn duplicates(&self) -> Option<(&'static str, Vec<V::PrimaryKeyType>)> {
+        self.indexes.iter().find_map(|(name, index)| {
+            let keys = index.duplicates();
+            (!keys.is_empty()).then_some((*name, keys))
+        })
+    }
+
+    /// Removes a row, that is about to be borrowed mutably, until the next [`Self::update`].
+    pub(crate) fn unindex(&mut self, key: &V::PrimaryKeyType, value: &V) {
+        if !self.stale && !self.is_empty() {
+            self.remove(key, value);
+            self.pending.insert(key.clone());
+        }
+    }
+
+    /// Marks all rows as changed, e.g. before iterating over them mutably.
+    pub(crate) fn invalidate(&mut self) {
+        if !self.is_empty() {
+            self.stale = true;
+            self.pending.clear();
+        }
+    }
+
+    /// Indexes the rows, that were borrowed mutably, again.
+    ///
+    /// Returns the first violation of a unique index by these rows, which are indexed nonetheless.
+    pub(crate) fn update<'a>(
+        &mut self,
+        get: impl Fn(&V::PrimaryKeyType) -> Option<&'a V>,
+        all: impl Iterator<Item = (&'a V::PrimaryKeyType, &'a V)>,
+    ) -> Option<(&'static str, Vec<V::PrimaryKeyType>)>
+    where
+        V: 'a,
+    {
+        if self.stale {
+            self.rebuild(all);
+            return self.duplicates();
+        }
+        let mut violation = None;
+        for key in std::mem::take(&mut self.pending) {
+            if let Some(value) = get(&key) {
+                self.insert(&key, value);
+                if violation.is_none() {
+                    violation = self
+                        .conflict(value, Some(&key))
+                        .map(|(name, existing)| (name, vec![existing, key]));
+                }
+            }
+        }
+        violation
+    }
+
+    /// Rebuilds all indexes from scratch.
+    pub(crate) fn rebuild<'a>(&mut self, all: impl Iterator<Item = (&'a V::PrimaryKeyType, &'a V)>)
+    where
+        V: 'a,
+    {
+        for index in self.indexes.values_mut() {
+            index.clear();
+        }
+        for (key, value) in all {
+            self.insert(key, value);
+        }
+        self.stale = false;
+        self.pending.clear();
+    }
+
+    /// Gets all rows with an indexed key in `range`, ordered by the indexed key.
+    ///
+    /// Rows that are not indexed currently are checked with `get` and `all`.
+    /// Returns `None` if there is no index `name` with the key type `K`.
+    pub(crate) fn lookup<'a, K, R>(
+        &self,
+        name: &str,
+        range: R,
+        get: impl Fn(&V::PrimaryKeyType) -> Option<&'a V>,
+        all: impl Iterator<Item = (&'a V::PrimaryKeyType, &'a V)>,
+    ) -> Option<Vec<&'a V>>
+    where
+        V: 'static,
+        K: Ord + 'static,
+        R: RangeBounds<K>,
+    {
+        let index = self
+            .indexes
+            .get(name)?
+            .as_any()
+            .downcast_ref::<Index<V, K>>()?;
+
+        // Indexed keys with the primary keys stored by the table or index, which break ties
+        let mut found: Vec<(K, &V::PrimaryKeyType, &V)> = Vec::new();
+        if self.stale {
+            found.extend(
+                all.map(|(pk, v)| ((index.extractor)(v), pk, v))
+                    .filter(|(k, _, _)| range.contains(k)),
+            );
+        } else {
+            for (_, keys) in index.entries.range(range_ref(&range)) {
+                found.extend(keys.iter().filter_map(|pk| {
+                    let v = get(pk)?;
+                    Some(((index.extractor)(v), pk, v))
+                }));
+            }
+            for pk in &self.pending {
+                if let Some(v) = get(pk) {
+                    let k = (index.extractor)(v);
+                    if range.contains(&k) {
+                        found.push((k, pk, v));
+                    }
+                }
+            }
+        }
+        found.sort_by(|(a, pa, _), (b, pb, _)| a.cmp(b).then(pa.cmp(pb)));
+        Some(found.into_iter().map(|(_, _, v)| v).collect())
+    }
+}
+
+/// Converts range bounds by value into bounds by reference.
+fn range_ref<K, R: RangeBounds<K>>(range: &R) -> (std::ops::Bound<&K>, std::ops::Bound<&K>) {
+    (range.start_bound(), range.end_bound())
+}
+
+impl<V: PrimaryKey> Clone for Indexes<V>
+where
+    V::PrimaryKeyType: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            indexes: self
+                .indexes
+                .iter()
+                .map(|(name, index)| (*name, index.box_clone()))
+                .collect(),
+            pending: self.pending.clone(),
+            stale: self.stale,
+        }
+    }
+}
+
+impl<V: PrimaryKey> fmt::Debug for Indexes<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.indexes.keys()).finish()
+    }
+}
+
+/// Type erased [`Index`].
+trait DynIndex<V: PrimaryKey> {
+    fn insert(&mut self, key: &V::PrimaryKeyType, value: &V);
+    fn remove(&mut self, key: &V::PrimaryKeyType, value: &V);
+    fn clear(&mut self);
+    fn conflict(&self, value: &V, ignore: Option<&V::PrimaryKeyType>) -> Option<V::PrimaryKeyType>;
+    fn duplicates(&self) -> Vec<V::PrimaryKeyType>;
+    fn box_clone(&self) -> Box<dyn DynIndex<V> + Send + Sync>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+struct Index<V: PrimaryKey, K> {
+    extractor: Arc<dyn Fn(&V) -> K + Send + Sync>,
+    entries: BTreeMap<K, BTreeSet<V::PrimaryKeyType>>,
+    unique: bool,
+}
+
+impl<V, K> DynIndex<V> for Index<V, K>
+where
+    V: PrimaryKey + 'static,
+    V::PrimaryKeyType: Ord + Clone + Send + Sync + 'static,
+    K: Ord + Clone + Send + Sync + 'static,
+{
+    fn insert(&mut self, key: &V::PrimaryKeyType, value: &V) {
+        self.entries
+            .entry((self.extractor)(value))
+            .or_default()
+            .insert(key.clone());
+    }
+
+    fn remove(&mut self, key: &V::PrimaryKeyType, value: &V) {
+        let indexed = (self.extractor)(value);
+        if let Some(keys) = self.entries.get_mut(&indexed) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.entries.remove(&indexed);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn conflict(&self, value: &V, ignore: Option<&V::PrimaryKeyType>) -> Option<V::PrimaryKeyType> {
+        if !self.unique {
+            return None;
+        }
+        let keys = self.entries.get(&(self.extractor)(value))?;
+        keys.iter().find(|k| Some(*k) != ignore).cloned()
+    }
+
+    fn duplicates(&self) -> Vec<V::PrimaryKeyType> {
+        if !self.unique {
+            return Vec::new();
+        }
+        self.entries
+            .values()
+            .filter(|keys| keys.len() > 1)
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    fn box_clone(&self) -> Box<dyn DynIndex<V> + Send + Sync> {
+        Box::new(Index {
+            extractor: self.extractor.clone(),
+            entries: self.entries.clone(),
+            unique: self.unique,
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
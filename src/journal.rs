@@ -0,0 +1,286 @@
+//! Append-only write-ahead journal for the [`AtomicDatabase`](crate::atomic::AtomicDatabase).
+//!
+//! Instead of rewriting the whole file on every write, each committed write guard appends
+//! one line to a journal next to the database file, describing only the values that changed.
+//! Once the journal grows past the configured limits, it is compacted into the snapshot
+//! (the main database file) and truncated.
+//!
+//! Changes are computed by diffing the JSON representation of the data against the last
+//! persisted state, which is kept in memory. This trades some memory and CPU for far less I/O.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::error::Error;
+
+/// Configures the journal mode of an [`AtomicDatabase`](crate::atomic::AtomicDatabase).
+#[derive(Debug, Clone)]
+pub struct JournalOptions {
+    checkpoint_records: usize,
+    checkpoint_size: u64,
+}
+
+impl Default for JournalOptions {
+    fn default() -> Self {
+        Self {
+            checkpoint_records: 1024,
+            checkpoint_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl JournalOptions {
+    /// Creates the default options: a checkpoint every 1024 records or 16 MiB of journal.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compacts the journal into the snapshot after this many records.
+    pub fn checkpoint_records(mut self, records: usize) -> Self {
+        self.checkpoint_records = records;
+        self
+    }
+
+    /// Compacts the journal into the snapshot once it is larger than `bytes`.
+    pub fn checkpoint_size(mut self, bytes: u64) -> Self {
+        self.checkpoint_size = bytes;
+        self
+    }
+}
+
+/// A single change of a journal record, addressed by the path of object keys.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Op {
+    Set { path: Vec<String>, value: Value },
+    Remove { path: Vec<String> },
+}
+
+/// Open journal of a database file.
+pub(crate) struct Journal {
+    file: File,
+    options: JournalOptions,
+    /// Last persisted state, used for diffing.
+    snapshot: Value,
+    records: usize,
+    size: u64,
+}
+
+impl Journal {
+    /// Creates an empty journal, truncating any existing one.
+    pub(crate) fn create(
+        path: &Path,
+        options: JournalOptions,
+        snapshot: Value,
+    ) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.set_len(0)?;
+        file.sync_all()?;
+        Ok(Self {
+            file,
+            options,
+            snapshot,
+            records: 0,
+            size: 0,
+        })
+    }
+
+    /// Appends a record with the changes from the last persisted state to `value`.
+    ///
+    /// Returns `false` if nothing changed.
+    pub(crate) fn append(&mut self, value: Value) -> Result<bool, Error> {
+        let mut ops = Vec::new();
+        diff(&self.snapshot, &value, &mut Vec::new(), &mut ops);
+        if ops.is_empty() {
+            return Ok(false);
+        }
+
+        let mut line = serde_json::to_vec(&ops).map_err(|e| Error::Encoding(e.to_string()))?;
+        line.push(b'\n');
+        if let Err(e) = self
+            .file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data())
+        {
+            // Cut off a partially written record, so that later records stay readable
+            let _ = self.file.set_len(self.size);
+            return Err(e.into());
+        }
+
+        self.snapshot = value;
+        self.records += 1;
+        self.size += line.len() as u64;
+        Ok(true)
+    }
+
+    /// Whether no records were appended since the last checkpoint.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    /// Whether the journal should be compacted into the snapshot.
+    pub(crate) fn needs_checkpoint(&self) -> bool {
+        self.records >= self.options.checkpoint_records || self.size >= self.options.checkpoint_size
+    }
+
+    /// Replaces the last persisted state, e.g. after saving the full database.
+    pub(crate) fn set_snapshot(&mut self, snapshot: Value) {
+        self.snapshot = snapshot;
+    }
+
+    /// Truncates the journal, after its records were written to the snapshot.
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.records = 0;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Path of the journal belonging to the database file at `path`.
+pub(crate) fn journal_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or("db".as_ref()));
+    name.push(".journal");
+    path.with_file_name(name)
+}
+
+/// Replays all records of the journal on top of `value`, returning the number of applied records.
+///
+/// A torn last record, left behind by a crash during the append, is ignored.
+pub(crate) fn replay(path: &Path, value: &mut Value) -> Result<usize, Error> {
+    let file = BufReader::new(File::open(path)?);
+    let mut lines = file.lines().peekable();
+    let mut applied = 0;
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let ops: Vec<Op> = match serde_json::from_str(&line) {
+            Ok(ops) => ops,
+            Err(e) if lines.peek().is_none() => {
+                warn!("Ignoring incomplete journal record: {e}");
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for op in ops {
+            apply(value, op);
+        }
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+/// Converts the data into its JSON representation used for diffing.
+pub(crate) fn to_value<T: Serialize>(data: &T) -> Result<Value, Error> {
+    serde_json::to_value(data).map_err(|e| Error::Encoding(e.to_string()))
+}
+
+/// Collects the operations transforming `old` into `new`.
+fn diff(old: &Value, new: &Value, path: &mut Vec<String>, ops: &mut Vec<Op>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for key in old.keys().filter(|k| !new.contains_key(*k)) {
+                let mut path = path.clone();
+                path.push(key.clone());
+                ops.push(Op::Remove { path });
+            }
+            for (key, new) in new {
+                path.push(key.clone());
+                match old.get(key) {
+                    Some(old) => diff(old, new, path, ops),
+                    None => ops.push(Op::Set {
+                        path: path.clone(),
+                        value: new.clone(),
+                    }),
+                }
+                path.pop();
+            }
+        }
+        (old, new) if old != new => ops.push(Op::Set {
+            path: path.clone(),
+            value: new.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// Applies a single operation, creating missing intermediate objects.
+fn apply(value: &mut Value, op: Op) {
+    let (path, new) = match op {
+        Op::Set { path, value } => (path, Some(value)),
+        Op::Remove { path } => (path, None),
+    };
+    let Some((last, parents)) = path.split_last() else {
+        if let Some(new) = new {
+            *value = new;
+        }
+        return;
+    };
+
+    let mut current = value;
+    for key in parents {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert(Value::Null);
+    }
+    if !current.is_object() {
+        *current = Value::Object(Map::new());
+    }
+    let object = current.as_object_mut().unwrap();
+    match new {
+        Some(new) => {
+            object.insert(last.clone(), new);
+        }
+        None => {
+            object.remove(last);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, diff};
+    use serde_json::json;
+
+    #[test]
+    fn diff_and_apply() {
+        let old = json!({
+            "users": {"0": {"id": 0, "name": "Nils"}, "1": {"id": 1, "name": "Alice"}},
+            "settings": {"time": 0},
+        });
+        let new = json!({
+            "users": {"0": {"id": 0, "name": "Nils W."}, "2": {"id": 2, "name": "Bob"}},
+            "settings": {"time": 0},
+        });
+
+        let mut ops = Vec::new();
+        diff(&old, &new, &mut Vec::new(), &mut ops);
+        assert_eq!(ops.len(), 3);
+
+        let mut replayed = old.clone();
+        for op in ops {
+            apply(&mut replayed, op);
+        }
+        assert_eq!(replayed, new);
+    }
+
+    #[test]
+    fn diff_unchanged() {
+        let value = json!({"users": {"0": {"id": 0}}, "list": [1, 2]});
+        let mut ops = Vec::new();
+        diff(&value, &value, &mut Vec::new(), &mut ops);
+        assert!(ops.is_empty());
+    }
+}
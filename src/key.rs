@@ -0,0 +1,286 @@
+//! Primary keys of a [`Table`](crate::table::Table).
+//!
+//! In human-readable formats like JSON, tables are stored as maps with string keys. Keys
+//! serializing to a string are used as they are, all others (numbers, tuples, structs, ...)
+//! are stored as their compact JSON text, e.g. `"0"` or `"[1,\"admins\"]"`.
+//!
+//! Strings, that are valid JSON text themselves, are stored quoted, e.g. `"\"null\""`,
+//! so they can't be confused with another key like `None`. So are strings starting with `$`,
+//! which is reserved for entries like the sequence of generated keys.
+
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
+use serde_json::Value;
+use std::fmt::Debug;
+
+/// Requirements of primary keys, implemented for all types satisfying them.
+///
+/// Versions up to 0.8 required `FromStr + Display` instead of serde and stored keys by `Display`,
+/// see [Upgrading from 0.8](crate#upgrading-from-08).
+pub trait Key: Ord + Clone + Debug + Serialize + DeserializeOwned {}
+
+impl<K: Ord + Clone + Debug + Serialize + DeserializeOwned> Key for K {}
+
+/// Encodes a key as a string for the human-readable form.
+///
+/// The key is serialized to JSON text directly, without a [`Value`], which can't hold
+/// integers like `u128::MAX`.
+pub(crate) fn encode<K: Serialize>(key: &K) -> Result<String, serde_json::Error> {
+    let json = serde_json::to_string(key)?;
+    if !json.starts_with('"') {
+        return Ok(json);
+    }
+    let s: String = serde_json::from_str(&json)?;
+    if is_json(&s) || s.starts_with('$') {
+        Ok(json)
+    } else {
+        Ok(s)
+    }
+}
+
+/// Decodes a key encoded by [`encode`].
+pub(crate) fn decode<K: DeserializeOwned>(s: &str) -> Result<K, serde_json::Error> {
+    // Strings of files written before they were quoted are still accepted
+    serde_json::from_str(s).or_else(|e| K::deserialize(Value::String(s.into())).map_err(|_| e))
+}
+
+/// Whether the string is valid JSON text, which would be ambiguous as a key.
+fn is_json(s: &str) -> bool {
+    serde_json::from_str::<IgnoredAny>(s).is_ok()
+}
+
+/// Types with a smallest value, needed for the trailing components of a [`Prefix`] scan.
+pub trait MinValue {
+    /// Returns the smallest value of the type.
+    fn min_value() -> Self;
+}
+
+macro_rules! impl_min_value {
+    ($($ty:ty => $min:expr),* $(,)?) => {
+        $(
+            impl MinValue for $ty {
+                fn min_value() -> Self {
+                    $min
+                }
+            }
+        )*
+    };
+}
+
+impl_min_value!(
+    u8 => 0, u16 => 0, u32 => 0, u64 => 0, u128 => 0, usize => 0,
+    i8 => i8::MIN, i16 => i16::MIN, i32 => i32::MIN, i64 => i64::MIN,
+    i128 => i128::MIN, isize => isize::MIN,
+    bool => false, char => '\0', String => String::new(), () => (),
+);
+
+impl<T> MinValue for Option<T> {
+    fn min_value() -> Self {
+        None
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl MinValue for uuid::Uuid {
+    fn min_value() -> Self {
+        uuid::Uuid::nil()
+    }
+}
+
+#[cfg(feature = "ulid")]
+impl MinValue for ulid::Ulid {
+    fn min_value() -> Self {
+        ulid::Ulid::nil()
+    }
+}
+
+/// Composite keys, that can be scanned by their leading components `P`,
+/// see [`Table::prefix`](crate::table::Table::prefix).
+///
+/// A single leading component is passed as it is, several as a tuple.
+pub trait Prefix<P> {
+    /// Returns the smallest key starting with `prefix`.
+    fn first_with(prefix: &P) -> Self;
+    /// Whether the key starts with `prefix`.
+    fn starts_with(&self, prefix: &P) -> bool;
+}
+
+impl<A: Clone + PartialEq, B: MinValue> Prefix<A> for (A, B) {
+    fn first_with(prefix: &A) -> Self {
+        (prefix.clone(), B::min_value())
+    }
+    fn starts_with(&self, prefix: &A) -> bool {
+        self.0 == *prefix
+    }
+}
+
+impl<A: Clone + PartialEq, B: MinValue, C: MinValue> Prefix<A> for (A, B, C) {
+    fn first_with(prefix: &A) -> Self {
+        (prefix.clone(), B::min_value(), C::min_value())
+    }
+    fn starts_with(&self, prefix: &A) -> bool {
+        self.0 == *prefix
+    }
+}
+
+impl<A: Clone + PartialEq, B: Clone + PartialEq, C: MinValue> Prefix<(A, B)> for (A, B, C) {
+    fn first_with(prefix: &(A, B)) -> Self {
+        (prefix.0.clone(), prefix.1.clone(), C::min_value())
+    }
+    fn starts_with(&self, prefix: &(A, B)) -> bool {
+        self.0 == prefix.0 && self.1 == prefix.1
+    }
+}
+
+impl<A: Clone + PartialEq, B: MinValue, C: MinValue, D: MinValue> Prefix<A> for (A, B, C, D) {
+    fn first_with(prefix: &A) -> Self {
+        (
+            prefix.clone(),
+            B::min_value(),
+            C::min_value(),
+            D::min_value(),
+        )
+    }
+    fn starts_with(&self, prefix: &A) -> bool {
+        self.0 == *prefix
+    }
+}
+
+impl<A: Clone + PartialEq, B: Clone + PartialEq, C: MinValue, D: MinValue> Prefix<(A, B)>
+    for (A, B, C, D)
+{
+    fn first_with(prefix: &(A, B)) -> Self {
+        (
+            prefix.0.clone(),
+            prefix.1.clone(),
+            C::min_value(),
+            D::min_value(),
+        )
+    }
+    fn starts_with(&self, prefix: &(A, B)) -> bool {
+        self.0 == prefix.0 && self.1 == prefix.1
+    }
+}
+
+impl<A: Clone + PartialEq, B: Clone + PartialEq, C: Clone + PartialEq, D: MinValue>
+    Prefix<(A, B, C)> for (A, B, C, D)
+{
+    fn first_with(prefix: &(A, B, C)) -> Self {
+        (
+            prefix.0.clone(),
+            prefix.1.clone(),
+            prefix.2.clone(),
+            D::min_value(),
+        )
+    }
+    fn starts_with(&self, prefix: &(A, B, C)) -> bool {
+        self.0 == prefix.0 && self.1 == prefix.1 && self.2 == prefix.2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode};
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use std::fmt::Debug;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Date {
+        year: u16,
+        day: u16,
+    }
+
+    #[test]
+    fn key_encoding() {
+        assert_eq!(encode(&42usize).unwrap(), "42");
+        // like the `Display` form, without the range of a JSON number
+        assert_eq!(encode(&u128::MAX).unwrap(), u128::MAX.to_string());
+        assert_eq!(encode(&i128::MIN).unwrap(), i128::MIN.to_string());
+        assert_eq!(encode(&"Nils").unwrap(), "Nils");
+        assert_eq!(encode(&(1, "admins")).unwrap(), r#"[1,"admins"]"#);
+        assert_eq!(
+            encode(&Date { year: 2025, day: 1 }).unwrap(),
+            r#"{"year":2025,"day":1}"#
+        );
+
+        // strings, that are JSON text, are quoted
+        assert_eq!(encode(&"42").unwrap(), r#""42""#);
+        assert_eq!(encode(&"null").unwrap(), r#""null""#);
+        // so are reserved keys
+        assert_eq!(encode(&"$sequence").unwrap(), r#""$sequence""#);
+
+        assert_eq!(decode::<usize>("42").unwrap(), 42);
+        assert_eq!(decode::<i64>("-7").unwrap(), -7);
+        assert_eq!(decode::<String>(r#""42""#).unwrap(), "42");
+        // unquoted strings of older files
+        assert_eq!(decode::<String>("42").unwrap(), "42");
+        assert_eq!(decode::<String>("[1]").unwrap(), "[1]");
+        assert_eq!(
+            decode::<(u32, String)>(r#"[1,"admins"]"#).unwrap(),
+            (1, "admins".into())
+        );
+        assert_eq!(
+            decode::<Date>(r#"{"year":2025,"day":1}"#).unwrap(),
+            Date { year: 2025, day: 1 }
+        );
+        assert!(decode::<usize>("Nils").is_err());
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Group {
+        Admins,
+        Named(String),
+        Id(u32),
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Untagged {
+        Id(u32),
+        Name(String),
+    }
+
+    fn assert_unambiguous<K: Debug + PartialEq + Serialize + DeserializeOwned>(keys: &[K]) {
+        let encoded: Vec<String> = keys.iter().map(|k| encode(k).unwrap()).collect();
+        for (i, s) in encoded.iter().enumerate() {
+            assert_eq!(decode::<K>(s).unwrap(), keys[i], "{s}");
+            assert!(!encoded[..i].contains(s), "{:?} collides as {s}", keys[i]);
+        }
+    }
+
+    #[test]
+    fn key_roundtrip() {
+        assert_unambiguous(&[
+            None,
+            Some("null".to_string()),
+            Some("\"null\"".into()),
+            Some("".into()),
+            Some("Nils".into()),
+        ]);
+        assert_unambiguous(&[Some(1), None]);
+        assert_unambiguous(&[u128::MAX, 0]);
+        assert_unambiguous(&[i128::MIN, -1, i128::MAX]);
+        assert_unambiguous(&[(u128::MAX, "Nils".to_string())]);
+        assert_unambiguous(&[
+            Group::Admins,
+            Group::Named("Admins".into()),
+            Group::Named("\"Admins\"".into()),
+            Group::Id(1),
+        ]);
+        assert_unambiguous(&[
+            Untagged::Id(1),
+            Untagged::Name("1".into()),
+            Untagged::Name("Nils".into()),
+        ]);
+        assert_unambiguous(&[
+            "true".to_string(),
+            "\"true\"".into(),
+            " 1".into(),
+            "1".into(),
+            "{}".into(),
+            "$sequence".into(),
+        ]);
+    }
+}
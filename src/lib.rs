@@ -12,13 +12,46 @@ compile_error!(
 pub use paste;
 #[cfg(feature = "atomic")]
 pub use serde;
+#[cfg(feature = "atomic")]
+pub use serde_json;
+
+#[cfg(feature = "atomic")]
+pub use error::Error;
 
 #[cfg(feature = "atomic")]
 pub mod atomic;
 #[cfg(feature = "atomic")]
+pub mod backup;
+#[cfg(feature = "atomic")]
+pub mod compression;
+#[cfg(feature = "atomic")]
+pub mod error;
+#[cfg(feature = "atomic")]
+pub mod format;
+#[cfg(feature = "atomic")]
+pub mod index;
+#[cfg(feature = "atomic")]
+pub mod journal;
+#[cfg(feature = "atomic")]
+pub mod key;
+#[cfg(feature = "atomic")]
+pub mod lock;
+#[cfg(feature = "atomic")]
 pub mod macros;
 #[cfg(feature = "atomic")]
+pub mod migration;
+#[cfg(feature = "atomic")]
+pub mod recovery;
+#[cfg(feature = "atomic")]
+pub mod subscription;
+#[cfg(feature = "atomic")]
 pub mod table;
+#[cfg(feature = "atomic")]
+pub mod watch;
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
 #[cfg(feature = "encrypted")]
 pub mod encrypted;
+#[cfg(feature = "encrypted")]
+pub mod header;
@@ -0,0 +1,124 @@
+//! Advisory locking of database files across processes.
+//!
+//! Opening a database locks the file `<database>.lock` next to it for the lifetime of the handle.
+//! Writable databases hold an exclusive lock and write their process id into the lock file, so
+//! other processes fail with [`Error::Locked`] naming the owner. Read-only databases, see
+//! [`OpenOptions::read_only`](crate::atomic::OpenOptions::read_only) and
+//! `EncryptedOpenOptions::read_only`, share the lock with each other.
+//!
+//! The locks are advisory, they only protect against other users of this crate. The lock file
+//! is kept after closing the database, as removing it would race with other processes opening it.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::error::Error;
+
+/// Lock of a database file, which is released on drop.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    file: File,
+    exclusive: bool,
+}
+
+impl FileLock {
+    /// Locks the database at `path` exclusively, failing with [`Error::Locked`] if it is locked.
+    pub(crate) fn exclusive(path: &Path) -> Result<Self, Error> {
+        Self::acquire(path, true)
+    }
+
+    /// Locks the database at `path` shared, failing with [`Error::Locked`] if it is locked exclusively.
+    pub(crate) fn shared(path: &Path) -> Result<Self, Error> {
+        Self::acquire(path, false)
+    }
+
+    fn acquire(path: &Path, exclusive: bool) -> Result<Self, Error> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(path))?;
+        let result = match exclusive {
+            true => fs2::FileExt::try_lock_exclusive(&file),
+            false => fs2::FileExt::try_lock_shared(&file),
+        };
+        if let Err(e) = result {
+            if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                return Err(Error::Locked {
+                    pid: owner(&mut file),
+                });
+            }
+            return Err(e.into());
+        }
+        if exclusive {
+            file.set_len(0)?;
+            file.write_all(process::id().to_string().as_bytes())?;
+        }
+        Ok(Self { file, exclusive })
+    }
+
+    /// Whether other handles can hold the lock at the same time.
+    pub(crate) fn is_shared(&self) -> bool {
+        !self.exclusive
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Don't blame this process for locks of later shared handles
+        if self.exclusive {
+            let _ = self.file.set_len(0);
+        }
+        let _ = fs2::FileExt::unlock(&self.file);
+    }
+}
+
+/// Process id of the exclusive owner of a lock file, if known.
+fn owner(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+pub(crate) fn lock_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or("db".as_ref()));
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::FileLock;
+    use crate::error::Error;
+    use std::{fs, path::Path, process};
+
+    #[test]
+    fn exclusive_and_shared() {
+        let path = Path::new("./tests/lock_exclusive_and_shared.json");
+        let lock_path = super::lock_path(path);
+
+        let lock = FileLock::exclusive(path).unwrap();
+        let pid = Some(process::id());
+        assert!(matches!(FileLock::exclusive(path), Err(Error::Locked { pid: p }) if p == pid));
+        assert!(matches!(FileLock::shared(path), Err(Error::Locked { pid: p }) if p == pid));
+        drop(lock);
+
+        let first = FileLock::shared(path).unwrap();
+        let second = FileLock::shared(path).unwrap();
+        assert!(second.is_shared());
+        assert!(matches!(
+            FileLock::exclusive(path),
+            Err(Error::Locked { pid: None })
+        ));
+        drop((first, second));
+
+        drop(FileLock::exclusive(path).unwrap());
+        fs::remove_file(lock_path).unwrap();
+    }
+}
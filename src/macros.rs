@@ -26,8 +26,8 @@
 /// impl PrimaryKey for User {
 ///     type PrimaryKeyType = usize;
 ///
-///     fn primary_key(&self) -> &Self::PrimaryKeyType {
-///         &self.id
+///     fn primary_key(&self) -> Self::PrimaryKeyType {
+///         self.id
 ///     }
 /// }
 ///
@@ -40,8 +40,8 @@
 /// impl PrimaryKey for Criminal {
 ///     type PrimaryKeyType = String;
 ///
-///     fn primary_key(&self) -> &Self::PrimaryKeyType {
-///         &self.user_name
+///     fn primary_key(&self) -> Self::PrimaryKeyType {
+///         self.user_name.clone()
 ///     }
 /// }
 ///
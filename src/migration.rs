@@ -0,0 +1,189 @@
+//! Schema versioning of the persisted databases.
+//!
+//! A database declares the version of its schema with `VERSION` on
+//! [`DataStore`](crate::atomic::DataStore) (or `EncryptedDataStore`), which is stored in the file.
+//! Version `0` is the default and keeps the file format of unversioned databases.
+//!
+//! On loading an older file, `migrate` is called for every version step, e.g. `migrate(1, ..)`
+//! upgrades the data from version 1 to version 2. Before the upgraded data is persisted, the
+//! original file is copied to `<file>.v<version>.bak`. Files of a newer version than the
+//! declared one are rejected with [`Error::Version`].
+//!
+//! ```
+//! use light_magic::{
+//!     atomic::DataStore,
+//!     serde::{Deserialize, Serialize},
+//!     serde_json::Value,
+//!     Error,
+//! };
+//!
+//! #[derive(Default, Serialize, Deserialize)]
+//! struct Database {
+//!     // renamed from `name` in version 1
+//!     full_name: String,
+//! }
+//!
+//! impl DataStore for Database {
+//!     const VERSION: u32 = 1;
+//!
+//!     fn migrate(from: u32, mut value: Value) -> Result<Value, Error> {
+//!         if from == 0 {
+//!             let name = value["name"].take();
+//!             value["full_name"] = name;
+//!         }
+//!         Ok(value)
+//!     }
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+use crate::{error::Error, journal};
+
+/// Reserved key of the schema version in the root object of a JSON database.
+pub(crate) const VERSION_KEY: &str = "$version";
+
+/// Prefix of the versioned plaintext of an encrypted database, followed by the version.
+#[cfg(feature = "encrypted")]
+pub(crate) const VERSION_MAGIC: [u8; 8] = *b"LMSCHEMA";
+
+/// Serializes the data with its schema version as the first key.
+#[derive(Serialize)]
+pub(crate) struct Versioned<'a, T> {
+    #[serde(rename = "$version")]
+    pub(crate) version: u32,
+    #[serde(flatten)]
+    pub(crate) data: &'a T,
+}
+
+/// Reads only the schema version of a JSON database, `0` if it has none.
+pub(crate) fn json_version(bytes: &[u8]) -> Result<u32, Error> {
+    #[derive(Deserialize)]
+    struct Version {
+        #[serde(rename = "$version", default)]
+        version: u32,
+    }
+    Ok(serde_json::from_slice::<Version>(bytes)?.version)
+}
+
+/// Fails if the file is newer than the supported version.
+pub(crate) fn check(found: u32, supported: u32) -> Result<(), Error> {
+    if found > supported {
+        return Err(Error::Version { found, supported });
+    }
+    Ok(())
+}
+
+/// Runs the migration steps from version `from` up to `to`.
+pub(crate) fn migrate<D>(
+    from: u32,
+    to: u32,
+    mut data: D,
+    step: impl Fn(u32, D) -> Result<D, Error>,
+) -> Result<D, Error> {
+    for version in from..to {
+        data = step(version, data)?;
+        info!(
+            "Migrated database from version {version} to {}",
+            version + 1
+        );
+    }
+    Ok(data)
+}
+
+/// Path of the backup of the database file at `path` before migrating from `version`.
+pub(crate) fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or("db".as_ref()));
+    name.push(format!(".v{version}.bak"));
+    path.with_file_name(name)
+}
+
+/// Copies the database file, and its journal if any, before migrating from `version`.
+pub(crate) fn backup(path: &Path, version: u32) -> Result<PathBuf, Error> {
+    let backup = backup_path(path, version);
+    fs::copy(path, &backup)?;
+    let journal = journal::journal_path(path);
+    if journal.exists() {
+        fs::copy(journal, journal::journal_path(&backup))?;
+    }
+    info!("Saved backup of the database before migrating to '{backup:?}'");
+    Ok(backup)
+}
+
+/// Prefixes the encoded data with its schema version, unless it is `0`.
+#[cfg(feature = "encrypted")]
+pub(crate) fn encode_version(version: u32, data: Vec<u8>) -> Vec<u8> {
+    if version == 0 {
+        return data;
+    }
+    let mut out = Vec::with_capacity(VERSION_MAGIC.len() + 4 + data.len());
+    out.extend_from_slice(&VERSION_MAGIC);
+    out.extend_from_slice(&version.to_le_bytes());
+    out.extend_from_slice(&data);
+    out
+}
+
+/// Splits the schema version from the encoded data, `0` if it has none.
+#[cfg(feature = "encrypted")]
+pub(crate) fn decode_version(data: &[u8]) -> (u32, &[u8]) {
+    match data.strip_prefix(&VERSION_MAGIC[..]) {
+        Some(rest) if rest.len() >= 4 => {
+            let (version, rest) = rest.split_at(4);
+            (u32::from_le_bytes(version.try_into().unwrap()), rest)
+        }
+        _ => (0, data),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{json_version, migrate, Versioned};
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Data {
+        name: String,
+    }
+
+    #[test]
+    fn versioned_json() {
+        let data = Data {
+            name: "Nils".into(),
+        };
+        let s = serde_json::to_string(&Versioned {
+            version: 3,
+            data: &data,
+        })
+        .unwrap();
+        assert_eq!(s, r#"{"$version":3,"name":"Nils"}"#);
+        assert_eq!(json_version(s.as_bytes()).unwrap(), 3);
+        assert_eq!(json_version(br#"{"name":"Nils"}"#).unwrap(), 0);
+    }
+
+    #[test]
+    fn migration_steps() {
+        let steps = migrate(1, 4, Vec::new(), |from, mut data| {
+            data.push(from);
+            Ok(data)
+        })
+        .unwrap();
+        assert_eq!(steps, [1, 2, 3]);
+    }
+
+    #[test]
+    #[cfg(feature = "encrypted")]
+    fn versioned_bytes() {
+        use super::{decode_version, encode_version};
+
+        assert_eq!(encode_version(0, vec![1, 2]), [1, 2]);
+        let encoded = encode_version(7, vec![1, 2]);
+        assert_eq!(decode_version(&encoded), (7, &[1, 2][..]));
+        assert_eq!(decode_version(&[1, 2]), (0, &[1, 2][..]));
+    }
+}
@@ -0,0 +1,95 @@
+//! Recovery of orphaned temporary files after a crash.
+//!
+//! Databases are saved by writing a temporary file next to the database file (`.<name>~`),
+//! which is renamed over the database file once it is complete. A crash in between leaves the
+//! temporary file behind. By default, opening the database then fails with
+//! [`Error::OrphanedTmpFile`] until the file is deleted by hand.
+//!
+//! With [`RecoveryPolicy::AutoRecover`], the temporary file is inspected once the database is
+//! locked, see [`lock`](crate::lock). If it is a complete snapshot, that can be deserialized
+//! (or decrypted, which checks its authentication tag), it is newer than the database file and
+//! replaces it. Otherwise, the crash happened while writing it and it is discarded.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::{error, warn};
+
+use crate::error::Error;
+
+/// How an orphaned temporary file is handled when opening a database, see [`recovery`](self).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    /// Fails with [`Error::OrphanedTmpFile`], the default.
+    #[default]
+    Strict,
+    /// Promotes the temporary file if it is a complete snapshot, otherwise discards it.
+    AutoRecover,
+}
+
+/// Returns the path of the temporary file of the database at `path`,
+/// handling an orphaned one according to the policy.
+///
+/// `validate` checks whether the content of the orphaned file is a complete snapshot.
+pub(crate) fn tmp_path(
+    path: &Path,
+    policy: RecoveryPolicy,
+    validate: impl FnOnce(&[u8]) -> Result<(), Error>,
+) -> Result<PathBuf, Error> {
+    let tmp = tmp_name(path);
+    if !tmp.exists() {
+        return Ok(tmp);
+    }
+    match policy {
+        RecoveryPolicy::Strict => {
+            error!(
+                "Found orphaned database temporary file '{tmp:?}'. \
+                 The server has recently crashed or is already running. \
+                 Delete this before continuing!"
+            );
+            Err(Error::OrphanedTmpFile(tmp))
+        }
+        RecoveryPolicy::AutoRecover => {
+            recover(path, &tmp, validate)?;
+            Ok(tmp)
+        }
+    }
+}
+
+/// Promotes or discards the orphaned temporary file `tmp`.
+fn recover(
+    path: &Path,
+    tmp: &Path,
+    validate: impl FnOnce(&[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if !path.exists() {
+        // The crash happened while creating the database
+        warn!("Discarding orphaned database temporary file '{tmp:?}' of a new database");
+        fs::remove_file(tmp)?;
+        return Ok(());
+    }
+    let result = fs::read(tmp)
+        .map_err(Error::from)
+        .and_then(|bytes| validate(&bytes));
+    match result {
+        Ok(()) => {
+            warn!("Recovering database from the complete orphaned temporary file '{tmp:?}'");
+            fs::rename(tmp, path)?;
+        }
+        Err(e) => {
+            warn!("Discarding incomplete orphaned database temporary file '{tmp:?}': {e}");
+            fs::remove_file(tmp)?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of the temporary file of the database at `path`.
+pub(crate) fn tmp_name(path: &Path) -> PathBuf {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or(OsStr::new("db")));
+    tmp_name.push("~");
+    path.with_file_name(tmp_name)
+}
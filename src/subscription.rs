@@ -0,0 +1,320 @@
+//! Change notifications of an [`AtomicDatabase`](crate::atomic::AtomicDatabase).
+//!
+//! Subscribers receive a [`Change`] for every inserted, updated or deleted row of a table once
+//! a write guard is released. This includes rows changed with
+//! [`Table::get_mut`](crate::table::Table::get_mut) or
+//! [`Table::values_mut`](crate::table::Table::values_mut).
+//!
+//! With [`PersistencePolicy::Immediate`](crate::atomic::PersistencePolicy::Immediate) and
+//! [`AtomicDatabaseWrite::commit`](crate::atomic::AtomicDatabaseWrite::commit), the changes were
+//! saved by then, unless saving failed, see
+//! [`AtomicDatabase::is_dirty`](crate::atomic::AtomicDatabase::is_dirty). With the other policies, they are published before they are saved in the
+//! background, so they may still be lost by a crash.
+//!
+//! Like the [`journal`], changes are computed by diffing the JSON representation
+//! of the data against the last published state. This state is only kept while there are
+//! subscribers, so a database without subscribers has no overhead.
+//!
+//! ```
+//! use light_magic::{
+//!     atomic::DataStore,
+//!     serde::{Deserialize, Serialize},
+//!     subscription::ChangeKind,
+//!     table::{PrimaryKey, Table},
+//! };
+//!
+//! #[derive(Default, Serialize, Deserialize)]
+//! struct Database {
+//!     users: Table<User>,
+//! }
+//!
+//! impl DataStore for Database {}
+//!
+//! #[derive(Clone, Serialize, Deserialize)]
+//! struct User {
+//!     id: usize,
+//!     name: String,
+//! }
+//!
+//! impl PrimaryKey for User {
+//!     type PrimaryKeyType = usize;
+//!
+//!     fn primary_key(&self) -> Self::PrimaryKeyType {
+//!         self.id
+//!     }
+//! }
+//!
+//! let db = Database::open_in_memory();
+//! let users = db.subscribe_table("users").unwrap();
+//! db.write().users.add(User { id: 0, name: "Nils".into() });
+//!
+//! let change = users.try_recv().unwrap();
+//! assert_eq!(change.kind(), ChangeKind::Insert);
+//! assert_eq!(change.key_as::<usize>(), Some(0));
+//! assert_eq!(change.new_as::<User>().unwrap().name, "Nils");
+//! ```
+
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{sync::mpsc, time::Duration};
+use tracing::error;
+
+use crate::{error::Error, journal, key, table::SEQUENCE_KEY};
+
+/// Kind of a [`Change`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A changed row of a table, with its JSON representation before and after the change.
+///
+/// Fields of the database, that are no tables, are reported like tables if they are
+/// serialized as objects (e.g. structs), otherwise as a whole without a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    table: String,
+    key: Option<String>,
+    old: Option<Value>,
+    new: Option<Value>,
+}
+
+impl Change {
+    /// Whether the row was inserted, updated or deleted.
+    pub fn kind(&self) -> ChangeKind {
+        match (&self.old, &self.new) {
+            (None, _) => ChangeKind::Insert,
+            (_, None) => ChangeKind::Delete,
+            _ => ChangeKind::Update,
+        }
+    }
+
+    /// Name of the table, the field of the database.
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    /// The primary key of the row, encoded like in the JSON file.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// Decodes the primary key of the row.
+    pub fn key_as<K: DeserializeOwned>(&self) -> Option<K> {
+        key::decode(self.key.as_deref()?).ok()
+    }
+
+    /// The row before the change, `None` if it was inserted.
+    pub fn old_value(&self) -> Option<&Value> {
+        self.old.as_ref()
+    }
+
+    /// The row after the change, `None` if it was deleted.
+    pub fn new_value(&self) -> Option<&Value> {
+        self.new.as_ref()
+    }
+
+    /// Deserializes the row before the change.
+    pub fn old_as<V: DeserializeOwned>(&self) -> Option<V> {
+        V::deserialize(self.old.as_ref()?).ok()
+    }
+
+    /// Deserializes the row after the change.
+    pub fn new_as<V: DeserializeOwned>(&self) -> Option<V> {
+        V::deserialize(self.new.as_ref()?).ok()
+    }
+}
+
+/// Receiver of the changes of a database, see
+/// [`AtomicDatabase::subscribe`](crate::atomic::AtomicDatabase::subscribe).
+///
+/// Dropping it ends the subscription.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: mpsc::Receiver<Change>,
+}
+
+impl Subscription {
+    /// Waits for the next change, `None` if the database was dropped.
+    pub fn recv(&self) -> Option<Change> {
+        self.receiver.recv().ok()
+    }
+
+    /// Waits for the next change up to `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Change> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    /// Returns the next change, if there is one already.
+    pub fn try_recv(&self) -> Option<Change> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Iterates over all changes received so far, without waiting.
+    pub fn try_iter(&self) -> impl Iterator<Item = Change> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Change;
+
+    /// Waits for the next change, see [`Subscription::recv`].
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+/// Subscribers of a database.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Senders with the table they are restricted to.
+    senders: Vec<(Option<String>, mpsc::Sender<Change>)>,
+    /// Last published state, `None` without subscribers.
+    snapshot: Option<Value>,
+}
+
+impl Subscribers {
+    /// Adds a subscriber for the changes of `table`, or all tables.
+    pub(crate) fn subscribe<T: Serialize>(
+        &self,
+        table: Option<&str>,
+        data: &T,
+    ) -> Result<Subscription, Error> {
+        let mut inner = self.inner.lock();
+        if inner.snapshot.is_none() {
+            inner.snapshot = Some(journal::to_value(data)?);
+        }
+        let (sender, receiver) = mpsc::channel();
+        inner.senders.push((table.map(Into::into), sender));
+        Ok(Subscription { receiver })
+    }
+
+    /// Sends the changes since the last call to the subscribers.
+    pub(crate) fn publish<T: Serialize>(&self, data: &T) {
+        let mut inner = self.inner.lock();
+        let Some(snapshot) = &inner.snapshot else {
+            return;
+        };
+        let value = match journal::to_value(data) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to publish changes: {e}");
+                return;
+            }
+        };
+        let changes = diff(snapshot, &value);
+        for change in changes {
+            // Drops the subscriptions, that were dropped by their receivers
+            inner.senders.retain(|(table, sender)| {
+                table.as_ref().map_or(false, |table| *table != change.table)
+                    || sender.send(change.clone()).is_ok()
+            });
+        }
+        inner.snapshot = (!inner.senders.is_empty()).then_some(value);
+    }
+}
+
+/// Collects the changed rows of the tables from `old` to `new`.
+fn diff(old: &Value, new: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return changes;
+    };
+    for (table, old_table) in old.iter().filter(|(k, _)| !new.contains_key(*k)) {
+        rows(table, Some(old_table), None, &mut changes);
+    }
+    for (table, new_table) in new {
+        let old_table = old.get(table);
+        if old_table != Some(new_table) {
+            rows(table, old_table, Some(new_table), &mut changes);
+        }
+    }
+    changes
+}
+
+/// Collects the changed rows of a table.
+fn rows(table: &str, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<Change>) {
+    let change = |key: Option<&String>, old: Option<&Value>, new: Option<&Value>| Change {
+        table: table.into(),
+        key: key.cloned(),
+        old: old.cloned(),
+        new: new.cloned(),
+    };
+    let empty = serde_json::Map::new();
+    match (old, new) {
+        (None | Some(Value::Object(_)), None | Some(Value::Object(_))) => {
+            let old = old.and_then(Value::as_object).unwrap_or(&empty);
+            let new = new.and_then(Value::as_object).unwrap_or(&empty);
+            // The sequence of generated keys is no row, other keys starting with `$` are quoted
+            let is_row = |key: &&String| key.as_str() != SEQUENCE_KEY;
+            for (key, old) in old.iter().filter(|(k, _)| is_row(k)) {
+                if !new.contains_key(key) {
+                    changes.push(change(Some(key), Some(old), None));
+                }
+            }
+            for (key, new) in new.iter().filter(|(k, _)| is_row(k)) {
+                let old = old.get(key);
+                if old != Some(new) {
+                    changes.push(change(Some(key), old, Some(new)));
+                }
+            }
+        }
+        (old, new) => changes.push(change(None, old, new)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, ChangeKind};
+    use serde_json::json;
+
+    #[test]
+    fn diff_rows() {
+        let old = json!({
+            "users": {"$sequence": "1", "0": {"id": 0, "name": "Nils"}, "1": {"id": 1, "name": "Alice"}},
+            "counter": 1,
+        });
+        let new = json!({
+            "users": {"$sequence": "2", "0": {"id": 0, "name": "Nils W."}, "2": {"id": 2, "name": "Bob"}},
+            "counter": 2,
+        });
+
+        let changes = diff(&old, &new);
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.table(), c.key(), c.kind()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("counter", None, ChangeKind::Update),
+                ("users", Some("1"), ChangeKind::Delete),
+                ("users", Some("0"), ChangeKind::Update),
+                ("users", Some("2"), ChangeKind::Insert),
+            ]
+        );
+        assert_eq!(
+            changes[2].old_value(),
+            Some(&json!({"id": 0, "name": "Nils"}))
+        );
+        assert_eq!(changes[3].key_as::<usize>(), Some(2));
+        assert!(diff(&new, &new).is_empty());
+
+        // Keys starting with `$` are quoted and only the sequence is skipped
+        let old = json!({"groups": {"$sequence": "1"}});
+        let new = json!({"groups": {"$sequence": "2", "\"$admins\"": {"name": "$admins"}}});
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind(), ChangeKind::Insert);
+        assert_eq!(changes[0].key_as::<String>(), Some("$admins".into()));
+    }
+}
@@ -1,24 +1,146 @@
 use serde::de::{Error as DeError, MapAccess, SeqAccess, Visitor};
-use serde::ser::{SerializeMap, SerializeSeq};
+use serde::ser::{SerializeMap, SerializeSeq, SerializeTuple};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fmt::{Debug, Display};
 use std::marker::PhantomData;
-use std::str::FromStr;
+use std::ops::{Bound, RangeBounds};
 use std::{
     clone::Clone,
-    collections::btree_map::{Values, ValuesMut},
+    collections::btree_map::{Iter, Keys, Range, Values, ValuesMut},
 };
 
+use tracing::warn;
+
+use crate::index::Indexes;
+use crate::key::{self, Key, Prefix};
+
+#[cfg(feature = "derive")]
+pub use light_magic_derive::PrimaryKey;
+
 /// Trait for getting the value of the primary key
 pub trait PrimaryKey {
     type PrimaryKeyType;
-    fn primary_key(&self) -> &Self::PrimaryKeyType;
+    /// Returns the primary key, which may be composed of several fields, e.g. as a tuple.
+    ///
+    /// The key is returned by value, so keys like `String` are cloned. Versions up to 0.8 returned a reference.
+    fn primary_key(&self) -> Self::PrimaryKeyType;
+
+    /// Enables generated keys with [`Table::insert_with`].
+    ///
+    /// The sequence of generated keys is persisted along the rows, which changes the serialized form of the table.
+    const AUTO_INCREMENT: bool = false;
+
+    /// Declares the secondary indexes of the table, see [`Indexes`].
+    fn indexes(_indexes: &mut Indexes<Self>)
+    where
+        Self: Sized,
+    {
+    }
+}
+
+/// Key types, that can be generated by [`Table::insert_with`].
+pub trait GenerateKey: Sized {
+    /// Generates the key following `last`, the largest key generated or stored so far.
+    ///
+    /// Returns `None` if no keys are left.
+    fn generate(last: Option<&Self>) -> Option<Self>;
+}
+
+macro_rules! impl_generate_key {
+    ($($ty:ty),*) => {
+        $(
+            impl GenerateKey for $ty {
+                fn generate(last: Option<&Self>) -> Option<Self> {
+                    match last {
+                        Some(last) => last.checked_add(1),
+                        None => Some(0),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_generate_key!(u32, u64, usize);
+
+/// Random version 4 UUIDs.
+#[cfg(feature = "uuid")]
+impl GenerateKey for uuid::Uuid {
+    fn generate(_last: Option<&Self>) -> Option<Self> {
+        Some(uuid::Uuid::new_v4())
+    }
+}
+
+/// ULIDs, that increase monotonically even within the same millisecond.
+#[cfg(feature = "ulid")]
+impl GenerateKey for ulid::Ulid {
+    fn generate(last: Option<&Self>) -> Option<Self> {
+        let new = ulid::Ulid::new();
+        match last {
+            Some(last) if new <= *last => last.increment(),
+            _ => Some(new),
+        }
+    }
+}
+
+/// Errors of changing a [`Table`], carrying the affected primary keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableError<K> {
+    /// An entry with this primary key already exists.
+    PrimaryKey(K),
+    /// No entry with this primary key exists.
+    NotFound(K),
+    /// The entry with the primary key `existing` has the same key in the unique index `index`.
+    Unique { index: &'static str, existing: K },
+    /// The entries with these primary keys have duplicate keys in the unique index `index`.
+    Duplicates { index: &'static str, keys: Vec<K> },
+    /// No more keys can be generated.
+    Exhausted,
+    /// Keys can't be generated, because [`PrimaryKey::AUTO_INCREMENT`] is not enabled.
+    AutoIncrementDisabled,
+    /// The created entry has the primary key `found` instead of the generated key `generated`.
+    KeyMismatch { generated: K, found: K },
+    /// There is no secondary index with this name and the requested key type.
+    UnknownIndex(String),
+}
+
+impl<K: Debug> Display for TableError<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TableError::PrimaryKey(key) => {
+                write!(f, "an entry with the key {key:?} already exists")
+            }
+            TableError::NotFound(key) => write!(f, "no entry with the key {key:?} exists"),
+            TableError::Unique { index, existing } => write!(
+                f,
+                "unique index '{index}' violated: the entry {existing:?} has the same value"
+            ),
+            TableError::Duplicates { index, keys } => write!(
+                f,
+                "unique index '{index}' violated: the entries {keys:?} have duplicate values"
+            ),
+            TableError::Exhausted => f.write_str("no more keys can be generated"),
+            TableError::AutoIncrementDisabled => {
+                f.write_str("generated keys require `PrimaryKey::AUTO_INCREMENT`")
+            }
+            TableError::KeyMismatch { generated, found } => write!(
+                f,
+                "the entry has the key {found:?} instead of the generated key {generated:?}"
+            ),
+            TableError::UnknownIndex(name) => {
+                write!(f, "no index '{name}' with the requested key type")
+            }
+        }
+    }
 }
 
+impl<K: Debug> std::error::Error for TableError<K> {}
+
 /// Represents a database table utilizing a `BTreeMap` for underlying data storage.
 /// Needs the `PrimaryKey` trait to be implemented for the value type. Offers
 /// enhanced methods for manipulating records, including `add`, `edit`, `delete`, `get`, and `search`.
+/// Lookups by other fields can be accelerated with secondary indexes, see [`Indexes`].
 /// ```
 /// use light_magic::{
 ///     serde::{Deserialize, Serialize},
@@ -35,54 +157,174 @@ pub trait PrimaryKey {
 /// impl PrimaryKey for User {
 ///     type PrimaryKeyType = usize;
 ///
-///     fn primary_key(&self) -> &Self::PrimaryKeyType {
-///         &self.id
+///     fn primary_key(&self) -> Self::PrimaryKeyType {
+///         self.id
 ///     }
 /// }
 /// ```
-#[derive(Default, Debug, Clone)]
+#[derive(Debug)]
 pub struct Table<V>
 where
     V: PrimaryKey + Serialize,
-    V::PrimaryKeyType: Ord + FromStr + Display + Debug + Clone,
-    <<V as PrimaryKey>::PrimaryKeyType as FromStr>::Err: std::fmt::Display,
+    V::PrimaryKeyType: Key,
 {
     inner: BTreeMap<<V as PrimaryKey>::PrimaryKeyType, V>,
+    indexes: Indexes<V>,
+    /// Last generated key, see [`PrimaryKey::AUTO_INCREMENT`].
+    sequence: Option<V::PrimaryKeyType>,
+}
+
+impl<V> Default for Table<V>
+where
+    V: PrimaryKey + Serialize,
+    V::PrimaryKeyType: Key,
+{
+    fn default() -> Self {
+        Self::from_inner(BTreeMap::new())
+    }
+}
+
+impl<V> Clone for Table<V>
+where
+    V: PrimaryKey + Serialize + Clone,
+    V::PrimaryKeyType: Key,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            indexes: self.indexes.clone(),
+            sequence: self.sequence.clone(),
+        }
+    }
+}
+
+impl<V> Table<V>
+where
+    V: PrimaryKey + Serialize,
+    V::PrimaryKeyType: Key,
+{
+    /// Creates the table and builds its indexes.
+    fn from_inner(inner: BTreeMap<V::PrimaryKeyType, V>) -> Self {
+        let mut indexes = Indexes::declared();
+        indexes.rebuild(inner.iter());
+        Self {
+            inner,
+            indexes,
+            sequence: None,
+        }
+    }
+
+    /// Indexes the rows again, that were borrowed mutably since the last change,
+    /// logging violations of the unique constraints.
+    fn update_indexes(&mut self) {
+        let inner = &self.inner;
+        if let Some((index, keys)) = self.indexes.update(|k| inner.get(k), inner.iter()) {
+            let error = TableError::Duplicates { index, keys };
+            warn!("Mutably borrowed entries violate a unique constraint: {error}");
+        }
+    }
+
+    /// Checks the unique constraints, e.g. after changing entries with [`Self::get_mut`]
+    /// or loading a file, which was edited by hand.
+    pub fn validate(&self) -> Result<(), TableError<V::PrimaryKeyType>> {
+        let mut indexes = self.indexes.clone();
+        let inner = &self.inner;
+        indexes.update(|k| inner.get(k), inner.iter());
+        match indexes.duplicates() {
+            Some((index, keys)) => Err(TableError::Duplicates { index, keys }),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs violations of the unique constraints in a loaded table, which is kept as it is.
+    fn report_duplicates(&self) {
+        if let Err(e) = self.validate() {
+            warn!("Loaded table violates a unique constraint: {e}");
+        }
+    }
+}
+
+impl<'a, V> IntoIterator for &'a Table<V>
+where
+    V: PrimaryKey + Serialize + for<'de> Deserialize<'de>,
+    V::PrimaryKeyType: Key,
+{
+    type Item = (&'a V::PrimaryKeyType, &'a V);
+    type IntoIter = Iter<'a, V::PrimaryKeyType, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
 impl<V> Serialize for Table<V>
 where
     V: PrimaryKey + Serialize + for<'a> Deserialize<'a>,
-    V::PrimaryKeyType: Ord + FromStr + Display + Debug + Clone,
-    <<V as PrimaryKey>::PrimaryKeyType as FromStr>::Err: std::fmt::Display,
+    V::PrimaryKeyType: Key,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         if serializer.is_human_readable() {
-            // Human-readable: emit as a map<String, V>
-            let mut map = serializer.serialize_map(Some(self.inner.len()))?;
+            // Human-readable: emit as a map<String, V> with encoded keys, starting with the sequence
+            let sequence = self.sequence.as_ref().filter(|_| V::AUTO_INCREMENT);
+            let len = self.inner.len() + usize::from(sequence.is_some());
+            let mut map = serializer.serialize_map(Some(len))?;
+            if let Some(sequence) = sequence {
+                map.serialize_entry(SEQUENCE_KEY, &encode_key::<_, S::Error>(sequence)?)?;
+            }
             for (k, v) in &self.inner {
-                map.serialize_entry(&k.to_string(), v)?;
+                map.serialize_entry(&encode_key::<_, S::Error>(k)?, v)?;
             }
             map.end()
+        } else if V::AUTO_INCREMENT {
+            // Binary with generated keys: emit as a tuple of the sequence and the rows
+            let mut tuple = serializer.serialize_tuple(2)?;
+            tuple.serialize_element(&self.sequence)?;
+            tuple.serialize_element(&Rows(&self.inner))?;
+            tuple.end()
         } else {
             // Binary (e.g., bincode): emit as a sequence of V with known length
-            let mut seq = serializer.serialize_seq(Some(self.inner.len()))?;
-            for v in self.inner.values() {
-                seq.serialize_element(v)?;
-            }
-            seq.end()
+            Rows(&self.inner).serialize(serializer)
+        }
+    }
+}
+
+/// Reserved key of the sequence in the human-readable form.
+pub(crate) const SEQUENCE_KEY: &str = "$sequence";
+
+/// Serializes the rows as a sequence, without their keys.
+struct Rows<'a, K, V>(&'a BTreeMap<K, V>);
+
+impl<K, V: Serialize> Serialize for Rows<'_, K, V> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for v in self.0.values() {
+            seq.serialize_element(v)?;
         }
+        seq.end()
     }
 }
 
+/// Encodes a primary key for the human-readable form, see [`key`].
+fn encode_key<K: Key, E: serde::ser::Error>(k: &K) -> Result<String, E> {
+    key::encode(k).map_err(|e| E::custom(format!("failed to encode primary key {k:?}: {e}")))
+}
+
+/// Decodes a primary key of the human-readable form.
+fn decode_key<K: Key, E: DeError>(k_str: &str) -> Result<K, E> {
+    key::decode(k_str)
+        .map_err(|e| E::custom(format!("failed to parse primary key '{}': {}", k_str, e)))
+}
+
 impl<'de, V> Deserialize<'de> for Table<V>
 where
     V: PrimaryKey + Serialize + Deserialize<'de>,
-    V::PrimaryKeyType: Ord + FromStr + Display + Debug + Clone,
-    <<V as PrimaryKey>::PrimaryKeyType as FromStr>::Err: std::fmt::Display,
+    V::PrimaryKeyType: Key,
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -95,8 +337,7 @@ where
             impl<'de, V> Visitor<'de> for MapVisitor<V>
             where
                 V: PrimaryKey + Serialize + Deserialize<'de>,
-                V::PrimaryKeyType: Ord + FromStr + Display + Debug + Clone,
-                <<V as PrimaryKey>::PrimaryKeyType as FromStr>::Err: std::fmt::Display,
+                V::PrimaryKeyType: Key,
             {
                 type Value = Table<V>;
 
@@ -109,17 +350,20 @@ where
                     A: MapAccess<'de>,
                 {
                     let mut inner = BTreeMap::new();
-                    while let Some((k_str, v)) = map.next_entry::<String, V>()? {
-                        let k = V::PrimaryKeyType::from_str(&k_str).map_err(|e| {
-                            A::Error::custom(format!(
-                                "failed to parse primary key '{}': {}",
-                                k_str, e
-                            ))
-                        })?;
+                    let mut sequence = None;
+                    while let Some(k_str) = map.next_key::<String>()? {
+                        if V::AUTO_INCREMENT && k_str == SEQUENCE_KEY {
+                            sequence = Some(decode_key(&map.next_value::<String>()?)?);
+                            continue;
+                        }
+                        let k = decode_key(&k_str)?;
                         // Optional: sanity check that v.primary_key() matches k
-                        inner.insert(k, v);
+                        inner.insert(k, map.next_value::<V>()?);
                     }
-                    Ok(Table { inner })
+                    let mut table = Table::from_inner(inner);
+                    table.sequence = sequence;
+                    table.report_duplicates();
+                    Ok(table)
                 }
             }
 
@@ -131,8 +375,7 @@ where
             impl<'de, V> Visitor<'de> for SeqVisitor<V>
             where
                 V: PrimaryKey + Serialize + Deserialize<'de>,
-                V::PrimaryKeyType: Ord + FromStr + Display + Debug + Clone,
-                <<V as PrimaryKey>::PrimaryKeyType as FromStr>::Err: std::fmt::Display,
+                V::PrimaryKeyType: Key,
             {
                 type Value = Table<V>;
 
@@ -145,15 +388,36 @@ where
                     A: SeqAccess<'de>,
                 {
                     let mut inner = BTreeMap::new();
-                    while let Some(v) = seq.next_element::<V>()? {
-                        let k = v.primary_key().clone();
-                        inner.insert(k, v);
+                    let mut sequence = None;
+                    if V::AUTO_INCREMENT {
+                        // Tuple of the sequence and the rows
+                        sequence = seq
+                            .next_element()?
+                            .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+                        let rows: Vec<V> = seq
+                            .next_element()?
+                            .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+                        for v in rows {
+                            inner.insert(v.primary_key(), v);
+                        }
+                    } else {
+                        while let Some(v) = seq.next_element::<V>()? {
+                            let k = v.primary_key();
+                            inner.insert(k, v);
+                        }
                     }
-                    Ok(Table { inner })
+                    let mut table = Table::from_inner(inner);
+                    table.sequence = sequence;
+                    table.report_duplicates();
+                    Ok(table)
                 }
             }
 
-            deserializer.deserialize_seq(SeqVisitor::<V>(PhantomData))
+            if V::AUTO_INCREMENT {
+                deserializer.deserialize_tuple(2, SeqVisitor::<V>(PhantomData))
+            } else {
+                deserializer.deserialize_seq(SeqVisitor::<V>(PhantomData))
+            }
         }
     }
 }
@@ -161,21 +425,80 @@ where
 impl<V> Table<V>
 where
     V: PrimaryKey + Serialize + for<'a> Deserialize<'a>,
-    V::PrimaryKeyType: Ord + FromStr + Display + Debug + Clone,
-    <<V as PrimaryKey>::PrimaryKeyType as FromStr>::Err: std::fmt::Display,
+    V::PrimaryKeyType: Key,
 {
-    /// Adds an entry to the table, returns the `value` or `None` if the `key` already exists in that table.
+    /// Adds an entry to the table, returns the `value` or `None` if the `key` already exists in that table
+    /// or a unique constraint is violated.
     pub fn add(&mut self, value: V) -> Option<V>
     where
         V: Clone,
         V::PrimaryKeyType: Clone,
+    {
+        self.try_add(value).ok()
+    }
+
+    /// Adds an entry to the table, returns the `value` or an error if the `key` already exists in that table
+    /// or a unique constraint is violated.
+    pub fn try_add(&mut self, value: V) -> Result<V, TableError<V::PrimaryKeyType>>
+    where
+        V: Clone,
     {
         let key = value.primary_key();
-        if !self.inner.contains_key(key) {
-            self.inner.insert(key.clone(), value.clone());
-            return Some(value);
+        if self.inner.contains_key(&key) {
+            return Err(TableError::PrimaryKey(key));
         }
-        None
+        self.update_indexes();
+        if let Some((index, existing)) = self.indexes.conflict(&value, None) {
+            return Err(TableError::Unique { index, existing });
+        }
+        self.indexes.insert(&key, &value);
+        self.inner.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Adds an entry with a generated key, returns the `value` or `None` if no key could be generated
+    /// or the entry is rejected, see [`Self::try_insert_with`].
+    pub fn insert_with<F>(&mut self, f: F) -> Option<V>
+    where
+        V: Clone,
+        V::PrimaryKeyType: GenerateKey,
+        F: FnOnce(V::PrimaryKeyType) -> V,
+    {
+        self.try_insert_with(f).ok()
+    }
+
+    /// Adds an entry with a generated key, which is passed to `f` for creating the `value`.
+    ///
+    /// Keys are never reused, even after deleting the entry with the largest key,
+    /// because the last generated key is persisted along the rows.
+    ///
+    /// Fails if [`PrimaryKey::AUTO_INCREMENT`] is not enabled for the row type
+    /// or the created entry doesn't have the generated key.
+    pub fn try_insert_with<F>(&mut self, f: F) -> Result<V, TableError<V::PrimaryKeyType>>
+    where
+        V: Clone,
+        V::PrimaryKeyType: GenerateKey,
+        F: FnOnce(V::PrimaryKeyType) -> V,
+    {
+        if !V::AUTO_INCREMENT {
+            return Err(TableError::AutoIncrementDisabled);
+        }
+        let last = self.sequence.as_ref().max(self.inner.keys().next_back());
+        let mut key = GenerateKey::generate(last).ok_or(TableError::Exhausted)?;
+        while self.inner.contains_key(&key) {
+            key = GenerateKey::generate(Some(&key)).ok_or(TableError::Exhausted)?;
+        }
+        let value = f(key.clone());
+        let found = value.primary_key();
+        if found != key {
+            return Err(TableError::KeyMismatch {
+                generated: key,
+                found,
+            });
+        }
+        let value = self.try_add(value)?;
+        self.sequence = Some(key);
+        Ok(value)
     }
 
     /// Gets an entry from the table, returns the `value` or `None` if it couldn't find the `value`.
@@ -184,28 +507,117 @@ where
     }
 
     /// Gets a mutable entry from the table, returns the `value` or `None` if it couldn't find the `value`.
+    ///
+    /// The indexes of the entry are updated on the next change of the table. Unique constraints
+    /// can't be enforced for changes made this way, violations are only logged then.
+    /// Use [`Self::try_modify`] to reject them.
     pub fn get_mut(&mut self, key: &V::PrimaryKeyType) -> Option<&mut V> {
-        self.inner.get_mut(key)
+        self.update_indexes();
+        let value = self.inner.get_mut(key)?;
+        self.indexes.unindex(key, value);
+        Some(value)
     }
 
-    /// Edits an entry in the table, returns the `new_value` or `None` if the entry couldn't be found.
+    /// Edits an entry in the table, returns the `new_value` or `None` if the entry couldn't be found,
+    /// the new key already exists or a unique constraint is violated.
     pub fn edit(&mut self, key: &V::PrimaryKeyType, new_value: V) -> Option<V>
     where
         V: Clone,
         V::PrimaryKeyType: Clone,
+    {
+        self.try_edit(key, new_value).ok()
+    }
+
+    /// Edits an entry in the table, returns the `new_value` or an error if the entry couldn't be found,
+    /// the new key already exists or a unique constraint is violated.
+    pub fn try_edit(
+        &mut self,
+        key: &V::PrimaryKeyType,
+        new_value: V,
+    ) -> Result<V, TableError<V::PrimaryKeyType>>
+    where
+        V: Clone,
     {
         let new_key = new_value.primary_key();
-        if (key == new_key || !self.inner.contains_key(new_key)) && self.inner.remove(key).is_some()
-        {
-            self.inner.insert(new_key.clone(), new_value.clone());
-            return Some(new_value);
+        if *key != new_key && self.inner.contains_key(&new_key) {
+            return Err(TableError::PrimaryKey(new_key));
         }
-        None
+        if !self.inner.contains_key(key) {
+            return Err(TableError::NotFound(key.clone()));
+        }
+        self.update_indexes();
+        if let Some((index, existing)) = self.indexes.conflict(&new_value, Some(key)) {
+            return Err(TableError::Unique { index, existing });
+        }
+        if let Some(old_value) = self.inner.remove(key) {
+            self.indexes.remove(key, &old_value);
+        }
+        self.indexes.insert(&new_key, &new_value);
+        self.inner.insert(new_key, new_value.clone());
+        Ok(new_value)
+    }
+
+    /// Changes an entry in place with `f`, returns the changed `value` or an error if the entry
+    /// couldn't be found or the change violates a constraint, see [`Self::try_edit`].
+    ///
+    /// The entry is only changed if all constraints hold.
+    pub fn try_modify<F>(
+        &mut self,
+        key: &V::PrimaryKeyType,
+        f: F,
+    ) -> Result<V, TableError<V::PrimaryKeyType>>
+    where
+        V: Clone,
+        F: FnOnce(&mut V),
+    {
+        let mut value = self
+            .inner
+            .get(key)
+            .cloned()
+            .ok_or_else(|| TableError::NotFound(key.clone()))?;
+        f(&mut value);
+        self.try_edit(key, value)
     }
 
     /// Deletes an entry from the table, returns the `value` or `None` if the `key` wasn't found.
     pub fn delete(&mut self, key: &V::PrimaryKeyType) -> Option<V> {
-        self.inner.remove(key)
+        self.update_indexes();
+        let value = self.inner.remove(key)?;
+        self.indexes.remove(key, &value);
+        Some(value)
+    }
+
+    /// Gets all entries with the `key` in the secondary index `name`, ordered by their primary key.
+    ///
+    /// Fails with [`TableError::UnknownIndex`] if the index doesn't exist or has a different key type.
+    pub fn get_by_index<K: Ord + 'static>(
+        &self,
+        name: &str,
+        key: &K,
+    ) -> Result<Vec<&V>, TableError<V::PrimaryKeyType>>
+    where
+        V: 'static,
+    {
+        self.range_by_index::<K, _>(name, (Bound::Included(key), Bound::Included(key)))
+    }
+
+    /// Gets all entries with a key in `range` in the secondary index `name`, ordered by that key.
+    ///
+    /// Fails with [`TableError::UnknownIndex`] if the index doesn't exist or has a different key type.
+    pub fn range_by_index<K, R>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> Result<Vec<&V>, TableError<V::PrimaryKeyType>>
+    where
+        V: 'static,
+        K: Ord + 'static,
+        R: RangeBounds<K>,
+    {
+        let inner = &self.inner;
+        self.indexes
+            .lookup(name, range, |k| inner.get(k), inner.iter())
+            .ok_or_else(|| TableError::UnknownIndex(name.into()))
     }
 
     /// Searches the table by a predicate function.
@@ -233,14 +645,83 @@ where
     }
 
     /// Gets a mutable iterator over the values of the map, in order by key.
+    ///
+    /// The indexes are rebuilt on the next change of the table, which logs violations of the
+    /// unique constraints. Use [`Self::try_modify`] to reject them.
     pub fn values_mut(&mut self) -> ValuesMut<'_, V::PrimaryKeyType, V> {
+        self.indexes.invalidate();
         self.inner.values_mut()
     }
+
+    /// Gets an iterator over the entries of the map, in order by key.
+    ///
+    /// Use `iter().rev()` to iterate in reverse order.
+    pub fn iter(&self) -> Iter<'_, V::PrimaryKeyType, V> {
+        self.inner.iter()
+    }
+
+    /// Gets an iterator over the keys of the map, in order.
+    pub fn keys(&self) -> Keys<'_, V::PrimaryKeyType, V> {
+        self.inner.keys()
+    }
+
+    /// Gets an iterator over the entries with a key in `range`, in order by key.
+    ///
+    /// Panics if the range start is greater than its end, like [`BTreeMap::range`].
+    pub fn range<R>(&self, range: R) -> Range<'_, V::PrimaryKeyType, V>
+    where
+        R: RangeBounds<V::PrimaryKeyType>,
+    {
+        self.inner.range(range)
+    }
+
+    /// Gets an iterator over the entries with a key greater than `key`, in order by key.
+    ///
+    /// Useful for cursor based pagination, e.g. `table.after(&last).take(n)`.
+    pub fn after(&self, key: &V::PrimaryKeyType) -> Range<'_, V::PrimaryKeyType, V> {
+        self.inner
+            .range::<V::PrimaryKeyType, _>((Bound::Excluded(key), Bound::Unbounded))
+    }
+
+    /// Gets all entries of a composite key starting with `prefix`, in order by key.
+    ///
+    /// The prefix consists of the leading components of the key, e.g. the user of a `(user, group)` key.
+    pub fn prefix<P>(&self, prefix: &P) -> Vec<&V>
+    where
+        V::PrimaryKeyType: Prefix<P>,
+    {
+        self.inner
+            .range(V::PrimaryKeyType::first_with(prefix)..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    /// Gets the entry with the smallest key.
+    pub fn first(&self) -> Option<&V> {
+        self.inner.values().next()
+    }
+
+    /// Gets the entry with the largest key.
+    pub fn last(&self) -> Option<&V> {
+        self.inner.values().next_back()
+    }
+
+    /// Returns the number of entries in the table.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if the table contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{PrimaryKey, Table};
+    use super::{PrimaryKey, Table, TableError};
+    use crate::index::Indexes;
     use serde::{Deserialize, Serialize};
 
     #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -252,9 +733,17 @@ mod test {
 
     impl PrimaryKey for User {
         type PrimaryKeyType = usize;
-        fn primary_key(&self) -> &Self::PrimaryKeyType {
-            &self.id
+        fn primary_key(&self) -> Self::PrimaryKeyType {
+            self.id
         }
+        fn indexes(indexes: &mut Indexes<Self>) {
+            indexes.add("name", |user: &User| user.name.clone());
+            indexes.add("age", |user: &User| user.age);
+        }
+    }
+
+    fn ids(users: Vec<&User>) -> Vec<usize> {
+        users.into_iter().map(|user| user.id).collect()
     }
 
     #[test]
@@ -292,4 +781,345 @@ mod test {
             assert_eq!(table.get(&i).unwrap().name, back.get(&i).unwrap().name);
         }
     }
+
+    #[test]
+    fn secondary_indexes() {
+        let mut table = Table::default();
+        for (id, name, age) in [(0, "Nils", 21), (1, "Alice", 42), (2, "Nils", 30)] {
+            table.add(User {
+                id,
+                name: name.into(),
+                age,
+            });
+        }
+        assert_eq!(
+            ids(table.get_by_index("name", &String::from("Nils")).unwrap()),
+            [0, 2]
+        );
+        assert_eq!(ids(table.range_by_index("age", 25usize..).unwrap()), [2, 1]);
+
+        table.edit(
+            &0,
+            User {
+                id: 3,
+                name: "Bob".into(),
+                age: 21,
+            },
+        );
+        table.delete(&1);
+        assert_eq!(
+            ids(table.get_by_index("name", &String::from("Nils")).unwrap()),
+            [2]
+        );
+        assert_eq!(
+            ids(table.get_by_index("name", &String::from("Bob")).unwrap()),
+            [3]
+        );
+        assert!(table.get_by_index("age", &42usize).unwrap().is_empty());
+
+        // mutable borrows are reflected before and after the next change
+        table.get_mut(&2).unwrap().age = 50;
+        assert_eq!(ids(table.get_by_index("age", &50usize).unwrap()), [2]);
+        assert!(table.get_by_index("age", &30usize).unwrap().is_empty());
+        table.values_mut().for_each(|user| user.name = "Eve".into());
+        assert_eq!(
+            ids(table.get_by_index("name", &String::from("Eve")).unwrap()),
+            [2, 3]
+        );
+        table.delete(&3);
+        assert_eq!(
+            ids(table.get_by_index("name", &String::from("Eve")).unwrap()),
+            [2]
+        );
+
+        // indexes are rebuilt on deserialization
+        let s = serde_json::to_string(&table).unwrap();
+        let back: Table<User> = serde_json::from_str(&s).unwrap();
+        assert_eq!(ids(back.get_by_index("age", &50usize).unwrap()), [2]);
+        assert_eq!(
+            ids(back
+                .clone()
+                .get_by_index("name", &String::from("Eve"))
+                .unwrap()),
+            [2]
+        );
+    }
+
+    #[test]
+    fn unknown_index() {
+        let table: Table<User> = Table::default();
+        assert_eq!(
+            table.get_by_index("email", &String::new()),
+            Err(TableError::UnknownIndex("email".into()))
+        );
+        // the index "age" has the key type `usize`
+        assert_eq!(
+            table.range_by_index("age", 0u32..),
+            Err(TableError::UnknownIndex("age".into()))
+        );
+    }
+
+    #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    struct Account {
+        id: usize,
+        email: String,
+    }
+
+    impl PrimaryKey for Account {
+        type PrimaryKeyType = usize;
+        fn primary_key(&self) -> Self::PrimaryKeyType {
+            self.id
+        }
+        fn indexes(indexes: &mut Indexes<Self>) {
+            indexes.unique("email", |account: &Account| account.email.clone());
+        }
+    }
+
+    fn account(id: usize, email: &str) -> Account {
+        Account {
+            id,
+            email: email.into(),
+        }
+    }
+
+    #[test]
+    fn unique_constraints() {
+        let mut table = Table::default();
+        table.try_add(account(0, "nils@example.com")).unwrap();
+        table.try_add(account(1, "alice@example.com")).unwrap();
+
+        assert_eq!(
+            table.try_add(account(1, "bob@example.com")),
+            Err(TableError::PrimaryKey(1))
+        );
+        assert_eq!(
+            table.try_add(account(2, "nils@example.com")),
+            Err(TableError::Unique {
+                index: "email",
+                existing: 0
+            })
+        );
+        assert!(table.add(account(2, "alice@example.com")).is_none());
+
+        // editing keeps its own value, but not the ones of others
+        table.try_edit(&0, account(0, "nils@example.com")).unwrap();
+        assert_eq!(
+            table.try_edit(&0, account(0, "alice@example.com")),
+            Err(TableError::Unique {
+                index: "email",
+                existing: 1
+            })
+        );
+        assert_eq!(
+            table.try_edit(&5, account(5, "eve@example.com")),
+            Err(TableError::NotFound(5))
+        );
+        table.try_edit(&0, account(3, "eve@example.com")).unwrap();
+        table.try_add(account(4, "nils@example.com")).unwrap();
+
+        // checked changes in place
+        assert_eq!(
+            table.try_modify(&4, |account| account.email = "eve@example.com".into()),
+            Err(TableError::Unique {
+                index: "email",
+                existing: 3
+            })
+        );
+        assert_eq!(table.get(&4).unwrap().email, "nils@example.com");
+        table
+            .try_modify(&4, |account| account.email = "bob@example.com".into())
+            .unwrap();
+        assert_eq!(table.try_modify(&5, |_| {}), Err(TableError::NotFound(5)));
+
+        // mutable borrows bypass the checks, but are found by validate
+        assert!(table.validate().is_ok());
+        table.get_mut(&4).unwrap().email = "eve@example.com".into();
+        assert_eq!(
+            table.validate(),
+            Err(TableError::Duplicates {
+                index: "email",
+                keys: vec![3, 4]
+            })
+        );
+
+        // duplicates don't prevent loading the table
+        let s = serde_json::to_string(&table).unwrap();
+        let back = serde_json::from_str::<Table<Account>>(&s).unwrap();
+        assert_eq!(back.len(), 3);
+        assert!(back.validate().is_err());
+    }
+
+    #[test]
+    fn ordered_iteration() {
+        let mut table = Table::default();
+        assert!(table.is_empty());
+        assert!(table.first().is_none());
+        for id in [3, 0, 4, 1, 2] {
+            table.add(User {
+                id,
+                ..Default::default()
+            });
+        }
+
+        assert_eq!(table.len(), 5);
+        assert_eq!(table.first().unwrap().id, 0);
+        assert_eq!(table.last().unwrap().id, 4);
+        assert_eq!(table.keys().copied().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        assert_eq!(
+            table.iter().rev().map(|(k, _)| *k).collect::<Vec<_>>(),
+            [4, 3, 2, 1, 0]
+        );
+        assert_eq!(ids(table.range(1..3).map(|(_, v)| v).collect()), [1, 2]);
+        assert_eq!(
+            ids(table.range(3..).rev().map(|(_, v)| v).collect()),
+            [4, 3]
+        );
+
+        // pagination with a cursor
+        let page: Vec<_> = table.after(&1).take(2).map(|(k, _)| *k).collect();
+        assert_eq!(page, [2, 3]);
+        assert_eq!(table.after(&4).count(), 0);
+    }
+
+    #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    struct Order {
+        id: u32,
+        item: String,
+    }
+
+    impl PrimaryKey for Order {
+        type PrimaryKeyType = u32;
+        const AUTO_INCREMENT: bool = true;
+        fn primary_key(&self) -> Self::PrimaryKeyType {
+            self.id
+        }
+    }
+
+    fn order(id: u32) -> Order {
+        Order {
+            id,
+            item: format!("item {id}"),
+        }
+    }
+
+    #[test]
+    fn auto_increment() {
+        let mut table = Table::default();
+        assert_eq!(table.insert_with(order).unwrap().id, 0);
+        assert_eq!(table.insert_with(order).unwrap().id, 1);
+        // manually added keys are skipped
+        table.add(order(5));
+        assert_eq!(table.insert_with(order).unwrap().id, 6);
+
+        // the sequence survives deleting the largest key and serialization
+        table.delete(&6);
+        let s = serde_json::to_string(&table).unwrap();
+        assert!(s.starts_with(r#"{"$sequence":"6","0":"#));
+        let mut back: Table<Order> = serde_json::from_str(&s).unwrap();
+        assert_eq!(back.len(), 3);
+        assert_eq!(back.insert_with(order).unwrap().id, 7);
+
+        // the entry has to use the generated key
+        assert_eq!(
+            back.try_insert_with(|_| order(100)),
+            Err(TableError::KeyMismatch {
+                generated: 8,
+                found: 100
+            })
+        );
+        assert!(back.get(&100).is_none());
+        assert_eq!(back.insert_with(order).unwrap().id, 8);
+
+        back.add(order(u32::MAX));
+        assert_eq!(back.try_insert_with(order), Err(TableError::Exhausted));
+    }
+
+    #[test]
+    #[cfg(feature = "encrypted")]
+    fn auto_increment_bincode() {
+        use crate::encrypted::bincode_cfg;
+
+        let mut table = Table::default();
+        table.insert_with(order);
+        table.insert_with(order);
+        table.delete(&1);
+        let bytes = bincode::serde::encode_to_vec(&table, bincode_cfg()).unwrap();
+        let (mut back, _): (Table<Order>, usize) =
+            bincode::serde::decode_from_slice(&bytes, bincode_cfg()).unwrap();
+        assert_eq!(back.keys().copied().collect::<Vec<_>>(), [0]);
+        assert_eq!(back.insert_with(order).unwrap().id, 2);
+    }
+
+    #[test]
+    fn auto_increment_disabled() {
+        let mut table = Table::default();
+        let result = table.try_insert_with(|id| User {
+            id,
+            ..Default::default()
+        });
+        assert_eq!(result, Err(TableError::AutoIncrementDisabled));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "ulid")]
+    fn ulid_keys_increase() {
+        use super::GenerateKey;
+
+        let mut last = None;
+        for _ in 0..100 {
+            let key = ulid::Ulid::generate(last.as_ref()).unwrap();
+            assert!(Some(key) > last);
+            last = Some(key);
+        }
+    }
+
+    #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    struct Membership {
+        user: usize,
+        group: String,
+        role: String,
+    }
+
+    impl PrimaryKey for Membership {
+        type PrimaryKeyType = (usize, String);
+        fn primary_key(&self) -> Self::PrimaryKeyType {
+            (self.user, self.group.clone())
+        }
+    }
+
+    fn membership(user: usize, group: &str) -> Membership {
+        Membership {
+            user,
+            group: group.into(),
+            role: "member".into(),
+        }
+    }
+
+    #[test]
+    fn composite_keys() {
+        let mut table = Table::default();
+        for (user, group) in [(1, "admins"), (0, "users"), (1, "users"), (2, ""), (1, "")] {
+            table.add(membership(user, group)).unwrap();
+        }
+        assert!(table.add(membership(1, "users")).is_none());
+        assert!(table.get(&(1, "admins".into())).is_some());
+
+        let groups = |user| {
+            table
+                .prefix(&user)
+                .into_iter()
+                .map(|m| m.group.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(groups(1), ["", "admins", "users"]);
+        assert_eq!(groups(2), [""]);
+        assert!(groups(3).is_empty());
+
+        let s = serde_json::to_string(&table).unwrap();
+        assert!(s.starts_with(r#"{"[0,\"users\"]":{"user":0,"#));
+        let back: Table<Membership> = serde_json::from_str(&s).unwrap();
+        assert_eq!(back.len(), 5);
+        assert_eq!(back.prefix(&1).len(), 3);
+    }
 }
@@ -0,0 +1,154 @@
+//! Hot reloading of the [`AtomicDatabase`](crate::atomic::AtomicDatabase), when its file is
+//! changed on disk by another program, e.g. edited by hand or synced from another host.
+//!
+//! A background thread polls the modification time and size of the file, see
+//! [`OpenOptions::watch`](crate::atomic::OpenOptions::watch). With the `notify` feature, changes are
+//! detected right away by the file system notifications of the OS (like inotify), and polling
+//! is only a fallback. The saves of the database itself are no changes.
+//!
+//! If the database has unsaved changes, because of the
+//! [`PersistencePolicy`](crate::atomic::PersistencePolicy) or a failed save, the
+//! [`ConflictPolicy`] decides whether the file or the in-memory data wins.
+//! Reloaded changes are published to the [`subscription`](crate::subscription)s.
+//!
+//! ```no_run
+//! use light_magic::{
+//!     atomic::{DataStore, OpenOptions},
+//!     serde::{Deserialize, Serialize},
+//!     watch::{WatchEvent, WatchOptions},
+//! };
+//! use std::time::Duration;
+//!
+//! #[derive(Default, Serialize, Deserialize)]
+//! struct Database {
+//!     counter: usize,
+//! }
+//!
+//! impl DataStore for Database {}
+//!
+//! let watch = WatchOptions::new()
+//!     .interval(Duration::from_millis(500))
+//!     .on_change(|event| {
+//!         if let WatchEvent::Failed(e) = event {
+//!             eprintln!("Failed to reload the database: {e}");
+//!         }
+//!     });
+//! let db = Database::open_with("./db.json", OpenOptions::new().watch(watch)).unwrap();
+//! ```
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use crate::error::Error;
+
+/// Which data wins, if the file changed while the database has unsaved changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Reloads the file, discarding the unsaved changes. The default.
+    #[default]
+    PreferFile,
+    /// Keeps the in-memory data and saves it over the changed file.
+    PreferMemory,
+}
+
+/// Change of the database file, which is passed to [`WatchOptions::on_change`].
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// The data was reloaded from the changed file.
+    Reloaded,
+    /// The file changed while there were unsaved changes, which were resolved by the policy.
+    Conflict(ConflictPolicy),
+    /// Handling the change failed, e.g. because the file is invalid. The in-memory data is kept.
+    Failed(Error),
+}
+
+type Callback = Arc<dyn Fn(&WatchEvent) + Send + Sync>;
+
+/// Options for watching the database file, see [`watch`](self).
+#[derive(Clone)]
+pub struct WatchOptions {
+    pub(crate) interval: Duration,
+    pub(crate) conflict: ConflictPolicy,
+    pub(crate) callback: Option<Callback>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            conflict: ConflictPolicy::default(),
+            callback: None,
+        }
+    }
+}
+
+impl WatchOptions {
+    /// Creates the default options: polling every second and preferring the file on conflicts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the interval for polling the file.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets which data wins on conflicts with unsaved changes.
+    pub fn conflict(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict = policy;
+        self
+    }
+
+    /// Sets the callback, which is called on the watching thread after every change of the file.
+    pub fn on_change(mut self, callback: impl Fn(&WatchEvent) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+}
+
+impl fmt::Debug for WatchOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchOptions")
+            .field("interval", &self.interval)
+            .field("conflict", &self.conflict)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
+
+/// Calls `changed` on file system notifications about the file at `path`.
+///
+/// Returns `None` if the notifications are not available, the file is still polled then.
+#[cfg(feature = "notify")]
+pub(crate) fn notify(
+    path: &std::path::Path,
+    changed: impl Fn() + Send + 'static,
+) -> Option<notify::RecommendedWatcher> {
+    use notify::Watcher;
+    use tracing::warn;
+
+    let name = path.file_name()?.to_owned();
+    // The file itself is replaced on every save, so its directory is watched
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let handler = move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if event.paths.iter().any(|p| p.file_name() == Some(&name)) {
+                changed();
+            }
+        }
+    };
+    let result = notify::recommended_watcher(handler).and_then(|mut watcher| {
+        watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    });
+    match result {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            warn!("Failed to watch the database file, polling it instead: {e}");
+            None
+        }
+    }
+}
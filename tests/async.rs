@@ -0,0 +1,166 @@
+use std::fs;
+
+use light_magic::{
+    asynchronous::AsyncDatabase,
+    atomic::{DataStore, OpenOptions},
+    journal::JournalOptions,
+    serde::{Deserialize, Serialize},
+    Error,
+};
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Database {
+    counter: usize,
+    items: Vec<String>,
+}
+
+impl DataStore for Database {}
+
+/// Helper struct that deletes the database files when dropped
+struct TempDbPath {
+    path: String,
+}
+
+impl TempDbPath {
+    fn new(test_name: &str) -> Self {
+        let path = format!("./tests/{}.json", test_name);
+        let temp = TempDbPath { path };
+        temp.remove();
+        temp
+    }
+
+    fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(format!("{}.journal", self.path));
+        let _ = fs::remove_file(format!("{}.lock", self.path));
+    }
+}
+
+impl Drop for TempDbPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+fn saved(path: &TempDbPath) -> Database {
+    serde_json::from_str(&fs::read_to_string(path.as_str()).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn in_memory() {
+    let db = AsyncDatabase::<Database>::open_in_memory();
+    db.write().await.counter += 1;
+    let mut guard = db.write().await;
+    guard.counter += 1;
+    guard.commit().await.unwrap();
+    assert_eq!(db.read().await.counter, 2);
+}
+
+#[tokio::test]
+async fn commit_saves() {
+    let db_path = TempDbPath::new("async_commit_saves");
+    let db = AsyncDatabase::<Database>::open(db_path.as_str())
+        .await
+        .unwrap();
+
+    let mut guard = db.write().await;
+    guard.items.push("Item 1".into());
+    guard.commit().await.unwrap();
+    assert_eq!(saved(&db_path).items, ["Item 1"]);
+
+    // Dropped guards are saved in the background
+    db.write().await.items.push("Item 2".into());
+    db.flush().await.unwrap();
+    assert_eq!(saved(&db_path).items, ["Item 1", "Item 2"]);
+    db.close().await.unwrap();
+
+    let db = AsyncDatabase::<Database>::open(db_path.as_str())
+        .await
+        .unwrap();
+    assert_eq!(db.read().await.items, ["Item 1", "Item 2"]);
+    db.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn concurrent_writes() {
+    let db_path = TempDbPath::new("async_concurrent_writes");
+    let options = OpenOptions::new().journal(JournalOptions::new());
+    let db = AsyncDatabase::<Database>::open_with(db_path.as_str(), options)
+        .await
+        .unwrap();
+
+    let tasks: Vec<_> = (0..50)
+        .map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.write().await.counter += 1;
+                // readers are not blocked by the saves
+                let _ = db.read().await.counter;
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(db.read().await.counter, 50);
+    db.close().await.unwrap();
+
+    let db = AsyncDatabase::<Database>::open(db_path.as_str())
+        .await
+        .unwrap();
+    assert_eq!(db.read().await.counter, 50);
+    db.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn read_only() {
+    let db_path = TempDbPath::new("async_read_only");
+    let db = AsyncDatabase::<Database>::open(db_path.as_str())
+        .await
+        .unwrap();
+    db.write().await.counter = 1;
+    db.close().await.unwrap();
+
+    let options = OpenOptions::new().read_only(true);
+    let db = AsyncDatabase::<Database>::open_with(db_path.as_str(), options)
+        .await
+        .unwrap();
+    assert!(db.is_read_only());
+    assert!(matches!(db.try_write().await, Err(Error::ReadOnly)));
+
+    // changes stay in memory
+    let mut guard = db.write().await;
+    guard.counter = 2;
+    assert!(matches!(guard.commit().await, Err(Error::ReadOnly)));
+    assert!(matches!(db.flush().await, Err(Error::ReadOnly)));
+    assert_eq!(db.read().await.counter, 2);
+    assert_eq!(saved(&db_path).counter, 1);
+}
+
+#[tokio::test]
+async fn failed_saves() {
+    let db_path = TempDbPath::new("async_failed_saves");
+    let db = AsyncDatabase::<Database>::open(db_path.as_str())
+        .await
+        .unwrap();
+
+    // A directory at the tmp path makes saving fail
+    let tmp = "./tests/.async_failed_saves.json~";
+    fs::create_dir(tmp).unwrap();
+
+    let mut guard = db.write().await;
+    guard.counter += 1;
+    assert!(matches!(guard.commit().await, Err(Error::Dirty)));
+    assert!(db.is_dirty());
+    assert!(matches!(db.try_write().await, Err(Error::Dirty)));
+
+    fs::remove_dir(tmp).unwrap();
+    db.flush().await.unwrap();
+    assert!(!db.is_dirty());
+    assert_eq!(saved(&db_path).counter, 1);
+    db.close().await.unwrap();
+}
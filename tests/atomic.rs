@@ -1,11 +1,25 @@
+use std::{
+    fs,
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+
 use light_magic::{
-    atomic::DataStore,
+    atomic::{AtomicDatabase, DataStore, OpenOptions, PersistencePolicy},
+    backup::BackupPolicy,
+    format::Format,
     join,
+    journal::JournalOptions,
+    recovery::RecoveryPolicy,
     serde::{Deserialize, Serialize},
+    subscription::ChangeKind,
     table::{PrimaryKey, Table},
+    watch::{ConflictPolicy, WatchEvent, WatchOptions},
+    Error,
 };
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 struct Database {
     users: Table<User>,
     permissions: Table<Permission>,
@@ -25,8 +39,8 @@ struct User {
 impl PrimaryKey for User {
     type PrimaryKeyType = usize;
 
-    fn primary_key(&self) -> &Self::PrimaryKeyType {
-        &self.id
+    fn primary_key(&self) -> Self::PrimaryKeyType {
+        self.id
     }
 }
 
@@ -39,8 +53,8 @@ struct Permission {
 impl PrimaryKey for Permission {
     type PrimaryKeyType = String;
 
-    fn primary_key(&self) -> &Self::PrimaryKeyType {
-        &self.user_name
+    fn primary_key(&self) -> Self::PrimaryKeyType {
+        self.user_name.clone()
     }
 }
 
@@ -59,8 +73,8 @@ struct Criminal {
 impl PrimaryKey for Criminal {
     type PrimaryKeyType = String;
 
-    fn primary_key(&self) -> &Self::PrimaryKeyType {
-        &self.user_name
+    fn primary_key(&self) -> Self::PrimaryKeyType {
+        self.user_name.clone()
     }
 }
 
@@ -70,9 +84,54 @@ struct Settings {
     password: String,
 }
 
+/// Helper struct that deletes the database files when dropped
+struct TempDbPath {
+    path: String,
+}
+
+impl TempDbPath {
+    fn new(test_name: &str) -> Self {
+        let path = format!("./tests/{}.json", test_name);
+        let temp = TempDbPath { path };
+        temp.remove();
+        temp
+    }
+
+    fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    fn journal(&self) -> String {
+        format!("{}.journal", self.path)
+    }
+
+    fn lock(&self) -> String {
+        format!("{}.lock", self.path)
+    }
+
+    fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(self.journal());
+        let _ = fs::remove_file(self.lock());
+    }
+}
+
+/// Simulates a crash, which leaves the files behind without saving them
+fn crash<T: DataStore>(db: AtomicDatabase<T>, db_path: &TempDbPath) {
+    std::mem::forget(db);
+    // the leaked handle still holds the lock, which the OS releases on a crash
+    fs::remove_file(db_path.lock()).unwrap();
+}
+
+impl Drop for TempDbPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
 #[test]
 fn normal_operations_in_persistence() {
-    let db = Database::open("./tests/test.json");
+    let db = Database::open("./tests/test.json").unwrap();
 
     // normal adding
     db.write().users.add(User {
@@ -147,6 +206,8 @@ fn normal_operations_in_persistence() {
     db.write().criminals.delete(&String::from("Nils W."));
 
     assert!(db.read().criminals.get(&String::from("Nils W.")).is_none());
+    drop(db);
+    fs::remove_file("./tests/test.json.lock").unwrap();
 }
 
 #[test]
@@ -290,17 +351,17 @@ fn joins() {
     for i in 0..4 {
         db.write().users.add(User {
             id: i,
-            name: String::from("Smth".to_string() + &i.to_string()),
+            name: "Smth".to_string() + &i.to_string(),
             kind: String::from("Young"),
         });
 
         db.write().permissions.add(Permission {
-            user_name: String::from("Smth".to_string() + &i.to_string()),
+            user_name: "Smth".to_string() + &i.to_string(),
             level: Level::Admin,
         });
 
         db.write().criminals.add(Criminal {
-            user_name: String::from("Smth".to_string() + &i.to_string()),
+            user_name: "Smth".to_string() + &i.to_string(),
             entry: String::from("No records until this day! Keep ur eyes pealed!"),
         });
     }
@@ -311,3 +372,671 @@ fn joins() {
     assert!(joined.len() == 1);
     assert!(joined[0].0.name == "Smth2");
 }
+
+#[test]
+fn journal_replay() {
+    let db_path = TempDbPath::new("journal_replay");
+
+    {
+        let options = OpenOptions::new().journal(JournalOptions::new());
+        let db = Database::open_with(db_path.as_str(), options).unwrap();
+        db.write().users.add(User {
+            id: 0,
+            name: String::from("Nils"),
+            kind: String::from("Young"),
+        });
+        db.write().settings.time = 1718744090;
+        // the changes only exist in the journal
+        crash(db, &db_path);
+    }
+
+    let journal = fs::read_to_string(db_path.journal()).unwrap();
+    assert_eq!(journal.lines().count(), 2);
+
+    {
+        let db = Database::open(db_path.as_str()).unwrap();
+        assert_eq!(db.read().users.get(&0).unwrap().name, "Nils");
+        assert_eq!(db.read().settings.time, 1718744090);
+    }
+
+    // without the journal mode, the journal is compacted and removed
+    assert!(!std::path::Path::new(&db_path.journal()).exists());
+}
+
+#[test]
+fn journal_checkpoint() {
+    let db_path = TempDbPath::new("journal_checkpoint");
+
+    let options = OpenOptions::new().journal(JournalOptions::new().checkpoint_records(2));
+    let db = Database::open_with(db_path.as_str(), options).unwrap();
+    for i in 0..3 {
+        db.write().users.add(User {
+            id: i,
+            name: format!("User {i}"),
+            kind: String::from("Young"),
+        });
+    }
+    // unchanged data does not produce a record
+    drop(db.write());
+
+    let journal = fs::read_to_string(db_path.journal()).unwrap();
+    assert_eq!(journal.lines().count(), 1);
+    let snapshot = fs::read_to_string(db_path.as_str()).unwrap();
+    assert!(snapshot.contains("User 1") && !snapshot.contains("User 2"));
+
+    crash(db, &db_path);
+    let db = Database::open(db_path.as_str()).unwrap();
+    assert_eq!(db.read().users.values().count(), 3);
+}
+
+#[test]
+fn corrupted_file() {
+    let db_path = TempDbPath::new("corrupted_file");
+    fs::write(db_path.as_str(), "{\n  \"users\": {\n    \"0\": }\n}").unwrap();
+
+    match Database::open(db_path.as_str()) {
+        Err(Error::Deserialize { line, column, .. }) => {
+            assert_eq!(line, 3);
+            assert_eq!(column, 10);
+        }
+        other => panic!("Expected a deserialization error, got {other:?}"),
+    }
+}
+
+#[test]
+fn orphaned_tmp_file() {
+    let db_path = TempDbPath::new("orphaned_tmp_file");
+    let tmp = "./tests/.orphaned_tmp_file.json~";
+    fs::write(tmp, "").unwrap();
+
+    let result = Database::open(db_path.as_str());
+    fs::remove_file(tmp).unwrap();
+    assert!(matches!(result, Err(Error::OrphanedTmpFile(_))));
+}
+
+#[derive(Debug, PartialEq)]
+enum TxError {
+    Aborted,
+    Database(String),
+}
+
+impl From<Error> for TxError {
+    fn from(e: Error) -> Self {
+        TxError::Database(e.to_string())
+    }
+}
+
+#[test]
+fn transactions() {
+    let db_path = TempDbPath::new("transactions");
+    let db = Database::open(db_path.as_str()).unwrap();
+
+    let user = User {
+        id: 0,
+        name: String::from("Nils"),
+        kind: String::from("Young"),
+    };
+
+    // failing transactions are rolled back
+    let result: Result<(), TxError> = db.transaction(|db| {
+        db.users.add(user.clone());
+        db.settings.time = 1718744090;
+        Err(TxError::Aborted)
+    });
+    assert_eq!(result, Err(TxError::Aborted));
+    assert!(db.read().users.get(&0).is_none());
+    assert_eq!(db.read().settings.time, 0);
+    assert!(!fs::read_to_string(db_path.as_str())
+        .unwrap()
+        .contains("Nils"));
+
+    // and so are panicking ones
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        db.transaction(|db| -> Result<(), TxError> {
+            db.users.add(user.clone());
+            panic!("handler crashed");
+        })
+    }));
+    assert!(result.is_err());
+    assert!(db.read().users.get(&0).is_none());
+
+    // successful ones are saved
+    let result: Result<usize, TxError> = db.transaction(|db| {
+        db.users.add(user.clone());
+        db.settings.time = 1718744090;
+        Ok(db.users.values().count())
+    });
+    assert_eq!(result, Ok(1));
+    assert!(fs::read_to_string(db_path.as_str())
+        .unwrap()
+        .contains("Nils"));
+
+    // explicit rollback of a guard
+    let mut tx = db.begin();
+    tx.users.delete(&0);
+    tx.rollback();
+    assert!(db.read().users.get(&0).is_some());
+}
+
+#[test]
+fn failed_saves() {
+    let db_path = TempDbPath::new("failed_saves");
+    let db = Database::open(db_path.as_str()).unwrap();
+
+    // block the temporary file, so that saving fails
+    let tmp = "./tests/.failed_saves.json~";
+    fs::create_dir(tmp).unwrap();
+
+    // a failed transaction restores the saved state, so nothing is left to save
+    let result: Result<(), TxError> = db.transaction(|db| {
+        db.settings.time = 3;
+        Ok(())
+    });
+    assert!(matches!(result, Err(TxError::Database(_))));
+    assert_eq!(db.read().settings.time, 0);
+    assert!(!db.is_dirty());
+
+    let mut guard = db.write();
+    guard.settings.time = 1;
+    assert!(guard.commit().is_err());
+    assert!(db.is_dirty());
+    assert!(matches!(db.try_write(), Err(Error::Dirty)));
+
+    // failed transactions are rolled back
+    let result: Result<(), TxError> = db.transaction(|db| {
+        db.settings.time = 2;
+        Ok(())
+    });
+    assert!(matches!(result, Err(TxError::Database(_))));
+    assert_eq!(db.read().settings.time, 1);
+
+    fs::remove_dir(tmp).unwrap();
+    db.flush().unwrap();
+    assert!(!db.is_dirty());
+    assert!(db.try_write().unwrap().commit().is_ok());
+    assert!(fs::read_to_string(db_path.as_str())
+        .unwrap()
+        .contains("\"time\": 1"));
+}
+
+fn saved_time(db_path: &TempDbPath) -> usize {
+    let saved: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(db_path.as_str()).unwrap()).unwrap();
+    saved["settings"]["time"].as_u64().unwrap() as usize
+}
+
+#[test]
+fn persistence_policies() {
+    let db_path = TempDbPath::new("persistence_policies");
+
+    // manual saves only on flush and drop
+    let options = OpenOptions::new().persistence(PersistencePolicy::Manual);
+    let db = Database::open_with(db_path.as_str(), options).unwrap();
+    db.write().settings.time = 1;
+    assert_eq!(saved_time(&db_path), 0);
+    db.flush().unwrap();
+    assert_eq!(saved_time(&db_path), 1);
+    db.write().settings.time = 2;
+    drop(db);
+    assert_eq!(saved_time(&db_path), 2);
+
+    // debounced writes are coalesced and saved in the background
+    let delay = Duration::from_millis(50);
+    let options = OpenOptions::new().persistence(PersistencePolicy::Debounced(delay));
+    let db = Database::open_with(db_path.as_str(), options).unwrap();
+    for _ in 0..100 {
+        db.write().settings.time += 1;
+    }
+    assert_eq!(saved_time(&db_path), 2);
+    thread::sleep(delay * 6);
+    assert_eq!(saved_time(&db_path), 102);
+
+    // commits are saved right away
+    let mut guard = db.write();
+    guard.settings.time = 0;
+    guard.commit().unwrap();
+    assert_eq!(saved_time(&db_path), 0);
+    db.write().settings.time = 3;
+    drop(db);
+    assert_eq!(saved_time(&db_path), 3);
+
+    // interval saves work with the journal
+    let options = OpenOptions::new()
+        .journal(JournalOptions::new())
+        .persistence(PersistencePolicy::Interval(delay));
+    let db = Database::open_with(db_path.as_str(), options).unwrap();
+    db.write().settings.time = 4;
+    thread::sleep(delay * 6);
+    let journal = fs::read_to_string(db_path.journal()).unwrap();
+    assert_eq!(journal.lines().count(), 1);
+    drop(db);
+    assert_eq!(saved_time(&db_path), 4);
+}
+
+#[test]
+fn subscriptions() {
+    let db = Database::open_in_memory();
+    let all = db.subscribe().unwrap();
+    let users = db.subscribe_table("users").unwrap();
+
+    let user = |id: usize, name: &str| User {
+        id,
+        name: name.into(),
+        kind: String::new(),
+    };
+    db.write().users.add(user(0, "Nils"));
+    db.write().settings.time = 1;
+    db.write().users.get_mut(&0).unwrap().name = "Nils W.".into();
+    db.write().users.delete(&0);
+
+    let kinds: Vec<_> = users.try_iter().map(|c| c.kind()).collect();
+    assert_eq!(
+        kinds,
+        [ChangeKind::Insert, ChangeKind::Update, ChangeKind::Delete]
+    );
+    let changes: Vec<_> = all.try_iter().collect();
+    assert_eq!(changes.len(), 4);
+    assert_eq!(changes[1].table(), "settings");
+    assert_eq!(changes[1].key(), Some("time"));
+    assert_eq!(changes[2].old_as::<User>(), Some(user(0, "Nils")));
+    assert_eq!(changes[2].new_as::<User>(), Some(user(0, "Nils W.")));
+
+    // rolled back transactions and dropped subscriptions receive nothing
+    drop(all);
+    let result: Result<(), TxError> = db.transaction(|db| {
+        db.users.add(user(1, "Alice"));
+        Err(TxError::Aborted)
+    });
+    assert_eq!(result, Err(TxError::Aborted));
+    assert!(users.try_recv().is_none());
+
+    drop(db);
+    assert!(users.recv().is_none());
+}
+
+#[test]
+fn storage_formats() {
+    let db_path = TempDbPath::new("storage_formats");
+    let path = std::path::Path::new(db_path.as_str());
+    let format = || Format::detect(&fs::read(path).unwrap()).unwrap();
+
+    let options = OpenOptions::new().format(Format::JsonCompact);
+    let db = Database::open_with(path, options).unwrap();
+    db.write().settings.time = 1;
+    drop(db);
+    assert_eq!(format(), Format::JsonCompact);
+
+    // existing files keep their format
+    let db = Database::open(path).unwrap();
+    assert_eq!(db.read().settings.time, 1);
+    drop(db);
+    assert_eq!(format(), Format::JsonCompact);
+
+    AtomicDatabase::<Database>::convert(path, Format::Json).unwrap();
+    assert_eq!(format(), Format::Json);
+    assert_eq!(saved_time(&db_path), 1);
+
+    #[cfg(feature = "bincode")]
+    {
+        let options = OpenOptions::new()
+            .format(Format::Bincode)
+            .journal(JournalOptions::new());
+        let db = Database::open_with(path, options).unwrap();
+        assert_eq!(format(), Format::Bincode);
+        db.write().settings.time = 2;
+        // the changes only exist in the journal
+        crash(db, &db_path);
+
+        let db = Database::open(path).unwrap();
+        assert_eq!(db.read().settings.time, 2);
+        drop(db);
+        assert_eq!(format(), Format::Bincode);
+
+        // binary files can't be migrated
+        assert!(matches!(Versioned::open(path), Err(Error::Migration(_))));
+
+        AtomicDatabase::<Database>::convert(path, Format::Json).unwrap();
+        assert_eq!(saved_time(&db_path), 2);
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+#[test]
+fn compressed_files() {
+    use light_magic::compression::Compression;
+
+    #[cfg(feature = "zstd")]
+    let compression = Compression::Zstd(0);
+    #[cfg(not(feature = "zstd"))]
+    let compression = Compression::Gzip(6);
+
+    let db_path = TempDbPath::new("compressed_files");
+    let path = std::path::Path::new(db_path.as_str());
+    let detect = || Compression::detect(&fs::read(path).unwrap()).unwrap();
+
+    let options = OpenOptions::new()
+        .compression(compression)
+        .journal(JournalOptions::new());
+    let db = Database::open_with(path, options).unwrap();
+    for id in 0..100 {
+        db.write().users.add(User {
+            id,
+            name: "Nils".into(),
+            kind: "Young".into(),
+        });
+    }
+    drop(db);
+    assert_eq!(detect(), compression);
+    let compressed = fs::metadata(path).unwrap().len();
+
+    // existing files keep their compression
+    let db = Database::open(path).unwrap();
+    assert_eq!(db.read().users.len(), 100);
+    drop(db);
+    assert_eq!(detect(), compression);
+
+    let options = OpenOptions::new().compression(Compression::None);
+    drop(Database::open_with(path, options).unwrap());
+    assert_eq!(detect(), Compression::None);
+    assert!(fs::metadata(path).unwrap().len() > compressed * 5);
+}
+
+/// Version 2 of a store, that renamed `name` to `full_name` (v0 -> v1) and added `age` (v1 -> v2).
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct Versioned {
+    full_name: String,
+    age: u32,
+}
+
+impl DataStore for Versioned {
+    const VERSION: u32 = 2;
+
+    fn migrate(from: u32, mut value: serde_json::Value) -> Result<serde_json::Value, Error> {
+        match from {
+            0 => {
+                let name = value["name"].take();
+                value["full_name"] = name;
+            }
+            1 => value["age"] = 21.into(),
+            _ => return Err(Error::Migration(format!("unknown version {from}"))),
+        }
+        Ok(value)
+    }
+}
+
+#[test]
+fn migrations() {
+    let db_path = TempDbPath::new("migrations");
+    let backup = format!("{}.v0.bak", db_path.as_str());
+    let original = "{\n  \"name\": \"Nils\"\n}";
+    fs::write(db_path.as_str(), original).unwrap();
+
+    {
+        let db = Versioned::open(db_path.as_str()).unwrap();
+        assert_eq!(db.read().full_name, "Nils");
+        assert_eq!(db.read().age, 21);
+    }
+    assert_eq!(fs::read_to_string(&backup).unwrap(), original);
+    let content = fs::read_to_string(db_path.as_str()).unwrap();
+    assert!(content.starts_with("{\n  \"$version\": 2,"));
+    fs::remove_file(&backup).unwrap();
+
+    // current files are loaded without migrating
+    let db = Versioned::open(db_path.as_str()).unwrap();
+    assert_eq!(db.read().full_name, "Nils");
+    drop(db);
+    assert!(!std::path::Path::new(&backup).exists());
+
+    // newer files are rejected
+    fs::write(db_path.as_str(), "{\"$version\": 3, \"full_name\": \"\"}").unwrap();
+    assert!(matches!(
+        Versioned::open(db_path.as_str()),
+        Err(Error::Version {
+            found: 3,
+            supported: 2
+        })
+    ));
+}
+
+#[test]
+fn locking() {
+    let db_path = TempDbPath::new("locking");
+    let db = Database::open(db_path.as_str()).unwrap();
+    db.write().settings.time = 1;
+
+    // a second handle, like one of another process, is rejected
+    match Database::open(db_path.as_str()) {
+        Err(e @ Error::Locked { pid: Some(pid) }) => {
+            assert_eq!(pid, std::process::id());
+            assert_eq!(
+                e.to_string(),
+                format!("The database is locked by PID {pid}")
+            );
+        }
+        other => panic!("Expected a locked database, got {other:?}"),
+    }
+    let read_only = OpenOptions::new().read_only(true);
+    assert!(matches!(
+        Database::open_with(db_path.as_str(), read_only.clone()),
+        Err(Error::Locked { .. })
+    ));
+    drop(db);
+
+    // read-only handles share the lock
+    let first = Database::open_with(db_path.as_str(), read_only.clone()).unwrap();
+    let second = Database::open_with(db_path.as_str(), read_only).unwrap();
+    assert!(first.is_read_only());
+    assert_eq!(second.read().settings.time, 1);
+    assert!(matches!(
+        Database::open(db_path.as_str()),
+        Err(Error::Locked { pid: None })
+    ));
+
+    // their changes are never saved
+    first.write().settings.time = 2;
+    assert!(matches!(first.flush(), Err(Error::ReadOnly)));
+    assert!(matches!(first.try_write(), Err(Error::ReadOnly)));
+    drop((first, second));
+    assert_eq!(saved_time(&db_path), 1);
+
+    let db = Database::open(db_path.as_str()).unwrap();
+    assert_eq!(db.read().settings.time, 1);
+}
+
+#[test]
+fn crash_recovery() {
+    let db_path = TempDbPath::new("crash_recovery");
+    let tmp = "./tests/.crash_recovery.json~";
+    let options = OpenOptions::new().recovery(RecoveryPolicy::AutoRecover);
+    let set_time = |time| {
+        let db = Database::open_with(db_path.as_str(), options.clone()).unwrap();
+        db.write().settings.time = time;
+    };
+
+    // a crash right before renaming the complete tmp file
+    set_time(1);
+    let old = fs::read(db_path.as_str()).unwrap();
+    set_time(2);
+    fs::copy(db_path.as_str(), tmp).unwrap();
+    fs::write(db_path.as_str(), &old).unwrap();
+
+    assert!(matches!(
+        Database::open(db_path.as_str()),
+        Err(Error::OrphanedTmpFile(_))
+    ));
+    let db = Database::open_with(db_path.as_str(), options.clone()).unwrap();
+    assert_eq!(db.read().settings.time, 2);
+    drop(db);
+
+    // a crash while writing the tmp file
+    set_time(3);
+    fs::write(tmp, &old[..old.len() / 2]).unwrap();
+
+    let db = Database::open_with(db_path.as_str(), options).unwrap();
+    assert_eq!(db.read().settings.time, 3);
+    assert!(!std::path::Path::new(tmp).exists());
+}
+
+#[test]
+fn read_only() {
+    let db_path = TempDbPath::new("read_only");
+    let options = OpenOptions::new().journal(JournalOptions::new());
+    let db = Database::open_with(db_path.as_str(), options).unwrap();
+    db.write().settings.time = 1;
+    let content = fs::read(db_path.as_str()).unwrap();
+
+    // a reporting tool inspects the database while it is opened
+    let reader = Database::open_read_only(db_path.as_str()).unwrap();
+    assert_eq!(reader.read().settings.time, 1);
+    assert!(!reader.reload().unwrap());
+
+    db.write().settings.time = 2;
+    assert!(reader.reload().unwrap());
+    assert_eq!(reader.read().settings.time, 2);
+
+    // nothing was written by the reader
+    drop(reader);
+    assert_eq!(fs::read(db_path.as_str()).unwrap(), content);
+    assert!(!std::path::Path::new("./tests/.read_only.json~").exists());
+}
+
+#[test]
+fn hot_reload() {
+    let db_path = TempDbPath::new("hot_reload");
+    // another program replaces the file, like a sync tool
+    let edit = |time: usize| {
+        let mut value: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(db_path.as_str()).unwrap()).unwrap();
+        value["settings"]["time"] = time.into();
+        let synced = format!("{}.synced", db_path.as_str());
+        fs::write(&synced, value.to_string()).unwrap();
+        fs::rename(synced, db_path.as_str()).unwrap();
+    };
+    let open = |options: OpenOptions, conflict: ConflictPolicy| {
+        let (sender, events) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let watch = WatchOptions::new()
+            .interval(Duration::from_millis(10))
+            .conflict(conflict)
+            .on_change(move |event| {
+                let event = match event {
+                    WatchEvent::Failed(_) => None,
+                    event => Some(format!("{event:?}")),
+                };
+                sender.lock().unwrap().send(event).unwrap();
+            });
+        let db = Database::open_with(db_path.as_str(), options.watch(watch)).unwrap();
+        (db, events)
+    };
+    let timeout = Duration::from_secs(5);
+
+    let (db, events) = open(
+        OpenOptions::new().persistence(PersistencePolicy::Immediate),
+        ConflictPolicy::PreferFile,
+    );
+    let changes = db.subscribe_table("settings").unwrap();
+    db.write().settings.time = 1;
+    // the own saves are no changes
+    assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+
+    edit(100);
+    assert_eq!(events.recv_timeout(timeout).unwrap().unwrap(), "Reloaded");
+    assert_eq!(db.read().settings.time, 100);
+    assert_eq!(changes.try_iter().count(), 2);
+
+    fs::write(db_path.as_str(), "invalid").unwrap();
+    assert_eq!(events.recv_timeout(timeout).unwrap(), None);
+    assert_eq!(db.read().settings.time, 100);
+    drop(db);
+
+    // unsaved changes win
+    let (db, events) = open(
+        OpenOptions::new().persistence(PersistencePolicy::Manual),
+        ConflictPolicy::PreferMemory,
+    );
+    db.write().settings.time = 2;
+    edit(2000);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap().unwrap(),
+        "Conflict(PreferMemory)"
+    );
+    assert_eq!(saved_time(&db_path), 2);
+    drop(db);
+
+    // the file wins
+    let (db, events) = open(
+        OpenOptions::new().persistence(PersistencePolicy::Manual),
+        ConflictPolicy::PreferFile,
+    );
+    db.write().settings.time = 3;
+    edit(3000);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap().unwrap(),
+        "Conflict(PreferFile)"
+    );
+    assert_eq!(db.read().settings.time, 3000);
+    drop(db);
+    assert_eq!(saved_time(&db_path), 3000);
+
+    // changes only in the journal are unsaved as well
+    let options = OpenOptions::new().journal(JournalOptions::new());
+    let (db, events) = open(options, ConflictPolicy::PreferFile);
+    db.write().settings.time = 4;
+    edit(4000);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap().unwrap(),
+        "Conflict(PreferFile)"
+    );
+    assert_eq!(db.read().settings.time, 4000);
+    drop(db);
+    assert_eq!(saved_time(&db_path), 4000);
+}
+
+#[test]
+fn backups() {
+    let db_path = TempDbPath::new("backups");
+    let dir = "./tests/backups";
+    let _ = fs::remove_dir_all(dir);
+    let backups = || {
+        let mut backups: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |e| e == "bak"))
+            .collect();
+        backups.sort();
+        backups
+    };
+    let backup_time =
+        |path: &std::path::Path| Database::open_read_only(path).unwrap().read().settings.time;
+
+    // every second write, keeping the last two
+    let policy = BackupPolicy::new().dir(dir).keep(2).commits(2);
+    let db = Database::open_with(db_path.as_str(), OpenOptions::new().backup(policy)).unwrap();
+    for time in 1..=6 {
+        db.write().settings.time = time;
+    }
+    let rotated = backups();
+    assert_eq!(rotated.len(), 2);
+    assert_eq!(backup_time(&rotated[0]), 4);
+    assert_eq!(backup_time(&rotated[1]), 6);
+
+    // a snapshot at any time
+    let snapshot = format!("{dir}/snapshot.json");
+    db.backup_to(&snapshot).unwrap();
+    assert_eq!(backup_time(snapshot.as_ref()), 6);
+    drop(db);
+
+    // in the background
+    fs::remove_dir_all(dir).unwrap();
+    let policy = BackupPolicy::new()
+        .dir(dir)
+        .interval(Duration::from_millis(20));
+    let db = Database::open_with(db_path.as_str(), OpenOptions::new().backup(policy)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    drop(db);
+    let scheduled = backups();
+    assert!(!scheduled.is_empty());
+    assert_eq!(backup_time(&scheduled[0]), 6);
+
+    fs::remove_dir_all(dir).unwrap();
+}
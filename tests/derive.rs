@@ -0,0 +1,122 @@
+use light_magic::{
+    atomic::DataStore,
+    serde::{Deserialize, Serialize},
+    table::{PrimaryKey, Table, TableError},
+};
+
+#[derive(Default, Debug, Serialize, Deserialize, DataStore)]
+struct Database {
+    users: Table<User>,
+    orders: Table<Order>,
+    memberships: Table<Membership>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, PrimaryKey)]
+struct User {
+    #[primary_key]
+    id: usize,
+    #[index]
+    name: String,
+    #[unique(name = "mail")]
+    email: String,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PrimaryKey)]
+struct Order {
+    #[primary_key(auto_increment)]
+    id: u64,
+    item: String,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PrimaryKey)]
+struct Membership {
+    #[primary_key]
+    user: usize,
+    #[primary_key]
+    group: String,
+}
+
+#[derive(Debug, Clone, PrimaryKey)]
+struct Tag(#[primary_key] String, #[index] u32);
+
+fn user(id: usize, name: &str, email: &str) -> User {
+    User {
+        id,
+        name: name.into(),
+        email: email.into(),
+    }
+}
+
+#[test]
+fn primary_keys() {
+    assert_eq!(user(3, "Nils", "").primary_key(), 3);
+    assert_eq!(Tag("rust".into(), 1).primary_key(), "rust");
+
+    let membership = Membership {
+        user: 1,
+        group: "admins".into(),
+    };
+    assert_eq!(membership.primary_key(), (1, "admins".to_string()));
+}
+
+#[test]
+fn derived_store() {
+    let db = Database::open_in_memory();
+    let mut data = db.write();
+
+    data.users.add(user(0, "Nils", "nils@example.com")).unwrap();
+    data.users.add(user(1, "Nils", "nils@example.org")).unwrap();
+    assert_eq!(
+        data.users
+            .get_by_index("name", &String::from("Nils"))
+            .unwrap()
+            .len(),
+        2
+    );
+    assert_eq!(
+        data.users.try_add(user(2, "Alice", "nils@example.com")),
+        Err(TableError::Unique {
+            index: "mail",
+            existing: 0
+        })
+    );
+
+    let order = data
+        .orders
+        .insert_with(|id| Order {
+            id,
+            item: "Book".into(),
+        })
+        .unwrap();
+    assert_eq!(order.id, 0);
+
+    for (user, group) in [(0, "admins"), (1, "admins"), (0, "users")] {
+        data.memberships.add(Membership {
+            user,
+            group: group.into(),
+        });
+    }
+    assert_eq!(data.memberships.prefix(&0).len(), 2);
+}
+
+#[cfg(feature = "encrypted")]
+#[test]
+fn derived_encrypted_store() {
+    use light_magic::encrypted::EncryptedDataStore;
+
+    #[derive(Default, Debug, Serialize, Deserialize, EncryptedDataStore)]
+    struct Secrets {
+        users: Table<User>,
+    }
+
+    let path = std::env::temp_dir().join(format!("derive_{}.enc", std::process::id()));
+    {
+        let db = Secrets::open(&path, "password").unwrap();
+        db.write().users.add(user(0, "Nils", "nils@example.com"));
+    }
+    let db = Secrets::open(&path, "password").unwrap();
+    assert!(db.read().users.get(&0).is_some());
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(path.with_extension("enc.lock")).unwrap();
+}
@@ -1,11 +1,15 @@
-use std::{fs, path::Path};
+use std::{fs, io, path::Path, time::Duration};
 
 use light_magic::{
-    encrypted::EncryptedDataStore,
+    backup::BackupPolicy,
+    encrypted::{decode, encode, generate_key, EncryptedDataStore, EncryptedOpenOptions, Secret},
+    header::{KdfAlgorithm, KdfParams, KeyMode},
+    recovery::RecoveryPolicy,
     serde::{Deserialize, Serialize},
+    Error,
 };
 
-#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Default, Clone, Serialize, Deserialize, Debug, PartialEq)]
 struct TestData {
     items: Vec<String>,
 }
@@ -25,23 +29,28 @@ impl TempDbPath {
         let _ = fs::create_dir_all("./tests");
 
         // Remove if it somehow already exists
-        let _ = fs::remove_file(&path);
-
-        TempDbPath { path }
+        let temp = TempDbPath { path };
+        temp.remove();
+        temp
     }
 
     fn as_str(&self) -> &str {
         &self.path
     }
+
+    fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(format!("{}.lock", self.path));
+    }
 }
 
 impl Drop for TempDbPath {
     fn drop(&mut self) {
-        let _ = fs::remove_file(&self.path);
+        self.remove();
     }
 }
 
-const PASSWORD: &'static str = "securepassword";
+const PASSWORD: &str = "securepassword";
 
 #[test]
 fn creation() {
@@ -145,15 +154,10 @@ fn password_change() {
             result.is_err(),
             "Loading with old password should have failed after password change"
         );
-        if let Err(e) = result {
-            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
-            assert!(
-                e.to_string().contains("Decryption failed")
-                    || e.to_string()
-                        .contains("HMAC verification failed: Data is corrupted or tampered"),
-                "Error message does not indicate decryption failure"
-            );
-        }
+        assert!(
+            matches!(result, Err(Error::Decryption)),
+            "Error does not indicate decryption failure"
+        );
     }
 }
 
@@ -176,15 +180,10 @@ fn wrong_password() {
             result.is_err(),
             "Loading with wrong password should have failed"
         );
-        if let Err(e) = result {
-            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
-            assert!(
-                e.to_string().contains("Decryption failed")
-                    || e.to_string()
-                        .contains("HMAC verification failed: Data is corrupted or tampered"),
-                "Error message does not indicate decryption failure"
-            );
-        }
+        assert!(
+            matches!(result, Err(Error::Decryption)),
+            "Error does not indicate decryption failure"
+        );
     }
 
     // Ensure data integrity by loading with the new password again
@@ -221,14 +220,527 @@ fn file_corruption() {
         corrupted_result.is_err(),
         "Loading a corrupted file should have failed"
     );
-    if let Err(e) = corrupted_result {
-        dbg!(e.to_string());
-        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
-        assert!(
-            e.to_string()
-                .contains("Failed to deserialize encrypted data")
-                || e.to_string().contains("Decryption failed"),
-            "Error message does not indicate corruption"
+    assert!(
+        matches!(
+            corrupted_result,
+            Err(Error::Encoding(_)) | Err(Error::Decryption)
+        ),
+        "Error does not indicate corruption"
+    );
+}
+
+/// Trivially cheap key derivation for tests.
+fn cheap_kdf() -> KdfParams {
+    KdfParams::new().memory_cost(8).time_cost(1).parallelism(1)
+}
+
+#[test]
+fn kdf_params() {
+    let db_path = TempDbPath::new("kdf_params");
+    let kdf = cheap_kdf().algorithm(KdfAlgorithm::Argon2i);
+    {
+        let options = EncryptedOpenOptions::new().kdf(kdf);
+        let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+        db.write().items.push("Item 1".to_string());
+    }
+
+    // Loaded with the stored parameters, not the default ones
+    let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(db.kdf(), Some(kdf));
+    assert_eq!(db.read().items, ["Item 1"]);
+
+    // Invalid parameters are rejected
+    let invalid = EncryptedOpenOptions::new().kdf(cheap_kdf().time_cost(0));
+    assert!(matches!(
+        TestData::open_with("./tests/kdf_params_invalid.db", PASSWORD, invalid),
+        Err(Error::KeyDerivation)
+    ));
+}
+
+#[test]
+fn rehash_kdf() {
+    let db_path = TempDbPath::new("rehash_kdf");
+    let stronger = cheap_kdf().time_cost(2);
+    {
+        let options = EncryptedOpenOptions::new().kdf(cheap_kdf());
+        let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+        db.write().items.push("Item 1".to_string());
+
+        assert!(matches!(
+            db.rehash_kdf("wrongpassword", stronger),
+            Err(Error::Decryption)
+        ));
+        db.rehash_kdf(PASSWORD, stronger).unwrap();
+        assert_eq!(db.kdf(), Some(stronger));
+        db.write().items.push("Item 2".to_string());
+    }
+
+    let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(db.kdf(), Some(stronger));
+    assert_eq!(db.read().items, ["Item 1", "Item 2"]);
+}
+
+#[test]
+fn raw_keys() {
+    let db_path = TempDbPath::new("raw_keys");
+    let keyfile = TempDbPath::new("raw_keys_keyfile");
+    let key = generate_key();
+    fs::write(keyfile.as_str(), key).unwrap();
+    {
+        let db = TestData::open_with_key(db_path.as_str(), &key).unwrap();
+        assert_eq!(db.key_mode(), KeyMode::Key);
+        assert_eq!(db.kdf(), None);
+        db.write().items.push("Item 1".to_string());
+    }
+
+    let db = TestData::open_with_keyfile(db_path.as_str(), keyfile.as_str()).unwrap();
+    assert_eq!(db.read().items, ["Item 1"]);
+    drop(db);
+
+    // Neither a password nor another key can open it
+    assert!(matches!(
+        TestData::open(db_path.as_str(), PASSWORD),
+        Err(Error::Decryption)
+    ));
+    assert!(matches!(
+        TestData::open_with_key(db_path.as_str(), &generate_key()),
+        Err(Error::Decryption)
+    ));
+
+    // Keyfiles have to contain a whole key
+    fs::write(keyfile.as_str(), "short").unwrap();
+    assert!(matches!(
+        TestData::open_with_keyfile(db_path.as_str(), keyfile.as_str()),
+        Err(Error::Encoding(_))
+    ));
+}
+
+#[test]
+fn rekey() {
+    let db_path = TempDbPath::new("rekey");
+    let key = generate_key();
+    {
+        let options = EncryptedOpenOptions::new().kdf(cheap_kdf());
+        let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+        db.write().items.push("Item 1".to_string());
+
+        db.rekey(Secret::Key(&key)).unwrap();
+        assert_eq!(db.key_mode(), KeyMode::Key);
+        db.write().items.push("Item 2".to_string());
+    }
+    {
+        let db = TestData::open_with_key(db_path.as_str(), &key).unwrap();
+        assert_eq!(db.read().items, ["Item 1", "Item 2"]);
+
+        // Back to a password, with the default parameters
+        db.rekey(Secret::Password("newpassword")).unwrap();
+        assert_eq!(db.kdf(), Some(KdfParams::default()));
+    }
+
+    assert!(TestData::open_with_key(db_path.as_str(), &key).is_err());
+    let db = TestData::open(db_path.as_str(), "newpassword").unwrap();
+    assert_eq!(db.read().items, ["Item 1", "Item 2"]);
+}
+
+#[test]
+fn key_slots() {
+    let db_path = TempDbPath::new("key_slots");
+    let recovery = generate_key();
+    {
+        let options = EncryptedOpenOptions::new().kdf(cheap_kdf());
+        let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+        db.write().items.push("Item 1".to_string());
+
+        let id = db.add_key_slot("recovery", Secret::Key(&recovery)).unwrap();
+        let second = db
+            .add_key_slot_with("operator", Secret::Password("operator"), cheap_kdf())
+            .unwrap();
+        let slots = db.key_slots();
+        let labels: Vec<_> = slots.iter().map(|slot| slot.label()).collect();
+        assert_eq!(labels, ["default", "recovery", "operator"]);
+        assert_eq!(slots[1].id(), id);
+        assert_eq!(slots[1].key_mode(), &KeyMode::Key);
+
+        db.revoke_key_slot(second).unwrap();
+        assert!(matches!(db.revoke_key_slot(second), Err(Error::KeySlot(_))));
+        assert!(matches!(
+            db.revoke_key_slot(db.key_slot()),
+            Err(Error::KeySlot(_))
+        ));
+    }
+
+    // Every slot opens the database
+    let db = TestData::open_with_key(db_path.as_str(), &recovery).unwrap();
+    assert_eq!(db.read().items, ["Item 1"]);
+    drop(db);
+    let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(db.key_slots().len(), 2);
+    drop(db);
+    assert!(matches!(
+        TestData::open(db_path.as_str(), "operator"),
+        Err(Error::Decryption)
+    ));
+}
+
+#[test]
+fn change_password_keeps_data() {
+    let db_path = TempDbPath::new("change_password_keeps_data");
+    let options = EncryptedOpenOptions::new().kdf(cheap_kdf());
+    let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+    db.write().items.push("Item 1".to_string());
+
+    // Only the key slot is re-wrapped, the encrypted data stays the same
+    let before = fs::read(db_path.as_str()).unwrap();
+    db.change_password("newpassword").unwrap();
+    let after = fs::read(db_path.as_str()).unwrap();
+    assert_eq!(before.len(), after.len());
+    assert_eq!(before[before.len() - 32..], after[after.len() - 32..]);
+    drop(db);
+
+    let db = TestData::open(db_path.as_str(), "newpassword").unwrap();
+    assert_eq!(db.read().items, ["Item 1"]);
+}
+
+#[test]
+fn header_tampering() {
+    let db_path = TempDbPath::new("header_tampering");
+    {
+        let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+        db.write().items.push("Item 1".to_string());
+    }
+
+    let mut bytes = fs::read(db_path.as_str()).unwrap();
+    assert!(bytes.starts_with(b"LMAGICDB"));
+    // Downgrade the format version, which is authenticated
+    bytes[8] = 0;
+    fs::write(db_path.as_str(), bytes).unwrap();
+
+    assert!(matches!(
+        TestData::open(db_path.as_str(), PASSWORD),
+        Err(Error::Decryption)
+    ));
+}
+
+#[derive(Debug, PartialEq)]
+enum TxError {
+    Aborted,
+    Database(String),
+}
+
+impl From<Error> for TxError {
+    fn from(e: Error) -> Self {
+        TxError::Database(e.to_string())
+    }
+}
+
+#[test]
+fn transactions() {
+    let db_path = TempDbPath::new("transactions");
+
+    {
+        let db = TestData::open(db_path.as_str(), PASSWORD).expect("Failed to create database");
+        db.write().items.push("Item 1".to_string());
+
+        let result: Result<(), TxError> = db.transaction(|data| {
+            data.items.push("Item 2".to_string());
+            Err(TxError::Aborted)
+        });
+        assert_eq!(result, Err(TxError::Aborted));
+        assert_eq!(db.read().items, vec!["Item 1".to_string()]);
+
+        let result: Result<(), Error> = db.transaction(|data| {
+            data.items.push("Item 3".to_string());
+            Ok(())
+        });
+        assert!(result.is_ok());
+    }
+
+    {
+        let db = TestData::open(db_path.as_str(), PASSWORD).expect("Failed to load database");
+        assert_eq!(
+            db.read().items,
+            vec!["Item 1".to_string(), "Item 3".to_string()]
         );
     }
 }
+
+#[test]
+fn failed_saves() {
+    let db_path = TempDbPath::new("failed_saves");
+    let db = TestData::open(db_path.as_str(), PASSWORD).expect("Failed to create database");
+
+    // block the temporary file, so that saving fails
+    let tmp = "./tests/.failed_saves.db~";
+    fs::create_dir(tmp).unwrap();
+
+    // a failed transaction restores the saved state, so nothing is left to save
+    let result: Result<(), TxError> = db.transaction(|db| {
+        db.items.push("Item 0".to_string());
+        Ok(())
+    });
+    assert!(matches!(result, Err(TxError::Database(_))));
+    assert!(db.read().items.is_empty());
+    assert!(!db.is_dirty());
+
+    let mut guard = db.write();
+    guard.items.push("Item 1".to_string());
+    assert!(guard.commit().is_err());
+    assert!(db.is_dirty());
+    assert!(matches!(db.try_write(), Err(Error::Dirty)));
+
+    fs::remove_dir(tmp).unwrap();
+    db.flush().expect("Failed to flush database");
+    assert!(!db.is_dirty());
+    assert!(db.try_write().is_ok());
+}
+
+/// Version 1 of [`TestData`], which also counts its items.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+struct VersionedData {
+    items: Vec<String>,
+    count: u64,
+}
+
+impl EncryptedDataStore for VersionedData {
+    const VERSION: u32 = 1;
+
+    fn migrate(from: u32, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        assert_eq!(from, 0);
+        let old: TestData = decode(&data)?;
+        encode(&VersionedData {
+            count: old.items.len() as u64,
+            items: old.items,
+        })
+    }
+}
+
+#[test]
+fn migrations() {
+    let db_path = TempDbPath::new("migrations");
+    let backup = format!("{}.v0.bak", db_path.as_str());
+    {
+        let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+        db.write().items.push("Item 1".into());
+    }
+    let original = fs::read(db_path.as_str()).unwrap();
+
+    {
+        let db = VersionedData::open(db_path.as_str(), PASSWORD).unwrap();
+        assert_eq!(db.read().count, 1);
+        assert_eq!(db.read().items, ["Item 1"]);
+    }
+    assert_eq!(fs::read(&backup).unwrap(), original);
+    fs::remove_file(&backup).unwrap();
+
+    // the upgraded file is loaded without migrating and rejected by older versions
+    let db = VersionedData::open(db_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(db.read().count, 1);
+    drop(db);
+    assert!(!Path::new(&backup).exists());
+    assert!(matches!(
+        TestData::open(db_path.as_str(), PASSWORD),
+        Err(Error::Version {
+            found: 1,
+            supported: 0
+        })
+    ));
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn compression() {
+    use light_magic::compression::Compression;
+
+    let db_path = TempDbPath::new("compression");
+    let items: Vec<String> = (0..1000).map(|i| format!("Item {}", i % 10)).collect();
+    let options = EncryptedOpenOptions::new()
+        .kdf(cheap_kdf())
+        .compression(Compression::Zstd(0));
+    {
+        let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+        db.write().items = items.clone();
+    }
+    let compressed = fs::metadata(db_path.as_str()).unwrap().len();
+
+    {
+        // the compression is detected and kept
+        let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+        assert_eq!(db.compression(), Compression::Zstd(0));
+        assert_eq!(db.read().items, items);
+        db.set_compression(Compression::None).unwrap();
+    }
+    assert!(fs::metadata(db_path.as_str()).unwrap().len() > compressed * 5);
+
+    let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(db.compression(), Compression::None);
+    assert_eq!(db.read().items, items);
+}
+
+#[test]
+fn locking() {
+    let db_path = TempDbPath::new("locking");
+    let options = EncryptedOpenOptions::new().kdf(cheap_kdf());
+    let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+
+    let pid = Some(std::process::id());
+    assert!(matches!(
+        TestData::open(db_path.as_str(), PASSWORD),
+        Err(Error::Locked { pid: p }) if p == pid
+    ));
+    drop(db);
+
+    let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+    assert!(db.read().items.is_empty());
+    db.write().items.push("Item 1".to_string());
+
+    let read_only = EncryptedOpenOptions::new().read_only(true);
+    assert!(matches!(
+        TestData::open_with(db_path.as_str(), PASSWORD, read_only.clone()),
+        Err(Error::Locked { pid: p }) if p == pid
+    ));
+    drop(db);
+
+    // read-only handles share the lock
+    let first = TestData::open_with(db_path.as_str(), PASSWORD, read_only.clone()).unwrap();
+    let second = TestData::open_with(db_path.as_str(), PASSWORD, read_only).unwrap();
+    assert!(first.is_read_only());
+    assert_eq!(second.read().items, ["Item 1"]);
+    assert!(matches!(
+        TestData::open(db_path.as_str(), PASSWORD),
+        Err(Error::Locked { pid: None })
+    ));
+
+    // their changes are never saved
+    first.write().items.push("Item 2".to_string());
+    assert!(matches!(first.flush(), Err(Error::ReadOnly)));
+    assert!(matches!(first.try_write(), Err(Error::ReadOnly)));
+    assert!(matches!(
+        first.add_key_slot("recovery", Secret::Key(&generate_key())),
+        Err(Error::ReadOnly)
+    ));
+    drop((first, second));
+
+    let db = TestData::open(db_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(db.read().items, ["Item 1"]);
+}
+
+#[test]
+fn crash_recovery() {
+    let db_path = TempDbPath::new("crash_recovery");
+    let tmp = "./tests/.crash_recovery.db~";
+    let options = EncryptedOpenOptions::new()
+        .kdf(cheap_kdf())
+        .recovery(RecoveryPolicy::AutoRecover);
+    let set_items = |items: &[&str]| {
+        let db = TestData::open_with(db_path.as_str(), PASSWORD, options.clone()).unwrap();
+        db.write().items = items.iter().map(|i| i.to_string()).collect();
+    };
+
+    // the complete tmp file is promoted
+    set_items(&["old"]);
+    let old = fs::read(db_path.as_str()).unwrap();
+    set_items(&["new"]);
+    fs::copy(db_path.as_str(), tmp).unwrap();
+    fs::write(db_path.as_str(), old).unwrap();
+    assert!(matches!(
+        TestData::open(db_path.as_str(), PASSWORD),
+        Err(Error::OrphanedTmpFile(_))
+    ));
+    let db = TestData::open_with(db_path.as_str(), PASSWORD, options.clone()).unwrap();
+    assert_eq!(db.read().items, ["new"]);
+    drop(db);
+
+    // a tmp file with a corrupted authentication tag is discarded
+    let mut content = fs::read(db_path.as_str()).unwrap();
+    *content.last_mut().unwrap() ^= 1;
+    set_items(&["current"]);
+    fs::write(tmp, content).unwrap();
+    let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+    assert_eq!(db.read().items, ["current"]);
+    assert!(!Path::new(tmp).exists());
+}
+
+#[test]
+fn read_only() {
+    let db_path = TempDbPath::new("read_only");
+    let options = EncryptedOpenOptions::new().kdf(cheap_kdf());
+    let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+    db.write().items.push("Item 1".into());
+
+    let reader = TestData::open_read_only(db_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(reader.read().items, ["Item 1"]);
+    assert!(!reader.reload().unwrap());
+
+    db.write().items.push("Item 2".into());
+    assert!(reader.reload().unwrap());
+    assert_eq!(reader.read().items, ["Item 1", "Item 2"]);
+
+    let content = fs::read(db_path.as_str()).unwrap();
+    drop(reader);
+    assert_eq!(fs::read(db_path.as_str()).unwrap(), content);
+    assert!(matches!(
+        TestData::open_read_only(db_path.as_str(), "wrong"),
+        Err(Error::Decryption)
+    ));
+}
+
+#[test]
+fn backup() {
+    let db_path = TempDbPath::new("backup");
+    let backup_path = TempDbPath::new("backup_snapshot");
+    let options = EncryptedOpenOptions::new().kdf(cheap_kdf());
+    let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+    db.write().items.push("Item 1".into());
+
+    db.backup_to(backup_path.as_str()).unwrap();
+    db.write().items.push("Item 2".into());
+    let backup = TestData::open_read_only(backup_path.as_str(), PASSWORD).unwrap();
+    assert_eq!(backup.read().items, ["Item 1"]);
+    assert!(!std::path::Path::new("./tests/.backup_snapshot.db~").exists());
+}
+
+#[test]
+fn backups() {
+    let db_path = TempDbPath::new("backups");
+    let dir = "./tests/encrypted_backups";
+    let _ = fs::remove_dir_all(dir);
+    let backups = || {
+        let mut backups: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |e| e == "bak"))
+            .collect();
+        backups.sort();
+        backups
+    };
+    let backup_items = |path: &Path| {
+        TestData::open_read_only(path, PASSWORD)
+            .unwrap()
+            .read()
+            .items
+            .clone()
+    };
+
+    // every second write, keeping the last two
+    let policy = BackupPolicy::new().dir(dir).keep(2).commits(2);
+    let options = EncryptedOpenOptions::new().kdf(cheap_kdf()).backup(policy);
+    let db = TestData::open_with(db_path.as_str(), PASSWORD, options).unwrap();
+    for i in 1..=6 {
+        db.write().items.push(format!("Item {i}"));
+    }
+    let rotated = backups();
+    assert_eq!(rotated.len(), 2);
+    assert_eq!(backup_items(&rotated[0]).len(), 4);
+    assert_eq!(backup_items(&rotated[1]).len(), 6);
+    drop(db);
+
+    // there is no background thread for backups at an interval
+    fs::remove_dir_all(dir).unwrap();
+    let policy = BackupPolicy::new()
+        .dir(dir)
+        .interval(Duration::from_millis(200));
+    let options = EncryptedOpenOptions::new().kdf(cheap_kdf()).backup(policy);
+    let result = TestData::open_with(db_path.as_str(), PASSWORD, options);
+    assert!(matches!(result, Err(Error::Io(e)) if e.kind() == io::ErrorKind::InvalidInput));
+    assert!(!Path::new(dir).exists());
+}